@@ -0,0 +1,203 @@
+use crate::protocols::ProtocolTable;
+use chrono::{DateTime, Datelike, FixedOffset, Timelike, Utc};
+use std::{collections::HashMap, net::IpAddr};
+
+// an hour holding less than this share of a profile's sessions is outside its usual hours.
+// a host active evenly around the clock has 1/24 per hour.
+const UNUSUAL_HOUR_SHARE: f64 = 1.0 / 48.0;
+// profiles with fewer sessions are too small to tell usual hours.
+const MIN_PROFILE_SESSIONS: u64 = 100;
+
+// time-of-day and day-of-week histogram of sessions.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Profile {
+    hours: [u64; 24],
+    // monday first
+    weekdays: [u64; 7],
+    total: u64,
+}
+
+impl Profile {
+    fn insert(&mut self, t: &DateTime<FixedOffset>) {
+        self.hours[t.hour() as usize] += 1;
+        self.weekdays[t.weekday().num_days_from_monday() as usize] += 1;
+        self.total += 1;
+    }
+
+    fn merge(&mut self, other: &Profile) {
+        for (h, cnt) in self.hours.iter_mut().zip(other.hours) {
+            *h += cnt;
+        }
+        for (d, cnt) in self.weekdays.iter_mut().zip(other.weekdays) {
+            *d += cnt;
+        }
+        self.total += other.total;
+    }
+
+    // hours that have sessions but fall below the usual share, with their session counts.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn unusual_hours(&self) -> Vec<(usize, u64)> {
+        if self.total < MIN_PROFILE_SESSIONS {
+            return Vec::new();
+        }
+        self.hours
+            .iter()
+            .enumerate()
+            .filter(|(_, cnt)| **cnt > 0 && (**cnt as f64) < self.total as f64 * UNUSUAL_HOUR_SHARE)
+            .map(|(hour, cnt)| (hour, *cnt))
+            .collect()
+    }
+}
+
+impl std::fmt::Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let join = |v: &[u64]| {
+            v.iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        write!(
+            f,
+            "{}\t{}\t{}",
+            self.total,
+            join(&self.hours),
+            join(&self.weekdays)
+        )
+    }
+}
+
+// activity profiles of local hosts and their server ports.
+// services are keyed by (host, port, protocol number).
+#[derive(Debug)]
+pub struct Activity {
+    offset: FixedOffset,
+    hosts: HashMap<IpAddr, Profile>,
+    services: HashMap<(IpAddr, u16, u8), Profile>,
+}
+
+impl Activity {
+    // hours and weekdays are counted in the given time zone.
+    pub fn new(offset: FixedOffset) -> Self {
+        Self {
+            offset,
+            hosts: HashMap::new(),
+            services: HashMap::new(),
+        }
+    }
+
+    pub fn offset(&self) -> FixedOffset {
+        self.offset
+    }
+
+    pub fn insert_host(&mut self, host: IpAddr, t: &DateTime<Utc>) {
+        let t = t.with_timezone(&self.offset);
+        self.hosts.entry(host).or_default().insert(&t);
+    }
+
+    pub fn insert_service(&mut self, key: (IpAddr, u16, u8), t: &DateTime<Utc>) {
+        let t = t.with_timezone(&self.offset);
+        self.services.entry(key).or_default().insert(&t);
+    }
+
+    // both must count in the same time zone.
+    pub fn merge(&mut self, other: Activity) {
+        for (host, profile) in other.hosts {
+            self.hosts.entry(host).or_default().merge(&profile);
+        }
+        for (key, profile) in other.services {
+            self.services.entry(key).or_default().merge(&profile);
+        }
+    }
+
+    pub fn print(&self, profiles: bool, protocols: &ProtocolTable) {
+        let mut hosts = self.hosts.iter().collect::<Vec<_>>();
+        hosts.sort_by(|a, b| a.0.cmp(b.0));
+        let mut services = self.services.iter().collect::<Vec<_>>();
+        services.sort_by(|a, b| a.0.cmp(b.0));
+
+        if profiles {
+            println!("\nhost activity {} entries", hosts.len());
+            println!("host\tsessions\thours(00-23)\tweekdays(mon-sun)");
+            for (host, profile) in &hosts {
+                println!("{host}\t{profile}");
+            }
+            println!("\nservice activity {} entries", services.len());
+            println!("host\tport\tproto\tsessions\thours(00-23)\tweekdays(mon-sun)");
+            for ((host, port, proto), profile) in &services {
+                let proto = protocols.protocol(*proto);
+                println!("{host}\t{port}\t{proto}\t{profile}");
+            }
+        }
+
+        let fmt_hours = |hours: Vec<(usize, u64)>| {
+            hours
+                .iter()
+                .map(|(h, cnt)| format!("{h:02}h:{cnt}"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let unusual = hosts
+            .iter()
+            .map(|(host, profile)| (**host, "-".to_string(), profile.unusual_hours()))
+            .chain(services.iter().map(|((host, port, proto), profile)| {
+                (
+                    *host,
+                    format!("{port}/{}", protocols.protocol(*proto)),
+                    profile.unusual_hours(),
+                )
+            }))
+            .filter(|(_, _, hours)| !hours.is_empty())
+            .collect::<Vec<_>>();
+        println!("\noff-hours activity {} entries", unusual.len());
+        println!("host\tservice\thours");
+        for (host, service, hours) in unusual {
+            println!("{host}\t{service}\t{}", fmt_hours(hours));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Activity, Profile};
+    use chrono::{DateTime, FixedOffset, TimeZone, Utc};
+    use std::net::IpAddr;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn at(hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 11, 13, hour, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn office_hours_host() {
+        let mut activity = Activity::new(FixedOffset::east_opt(0).unwrap());
+        for hour in 9..18 {
+            for _ in 0..20 {
+                activity.insert_host(ip("10.0.0.5"), &at(hour));
+            }
+        }
+        activity.insert_host(ip("10.0.0.5"), &at(3));
+        let profile = &activity.hosts[&ip("10.0.0.5")];
+        assert_eq!(profile.unusual_hours(), vec![(3, 1)]);
+        assert_eq!(profile.weekdays[0], 181);
+    }
+
+    #[test]
+    fn small_or_even_profiles_are_not_flagged() {
+        let mut activity = Activity::new(FixedOffset::east_opt(9 * 3600).unwrap());
+        for hour in 0..24 {
+            for _ in 0..10 {
+                activity.insert_host(ip("10.0.0.6"), &at(hour));
+            }
+        }
+        activity.insert_host(ip("10.0.0.7"), &at(3));
+        assert!(activity.hosts[&ip("10.0.0.6")].unusual_hours().is_empty());
+        assert!(activity.hosts[&ip("10.0.0.7")].unusual_hours().is_empty());
+        // 03:00 UTC is 12:00 in UTC+9
+        assert_eq!(activity.hosts[&ip("10.0.0.7")].hours[12], 1);
+        assert_eq!(Profile::default().total, 0);
+    }
+}
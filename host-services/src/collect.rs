@@ -0,0 +1,310 @@
+use crate::{
+    activity::Activity,
+    graph::Graph,
+    hosts::{Hosts, RoleConfig},
+    input,
+    inventory::Sightings,
+    net::IpMapper,
+    protocols::{self, ProtocolTable},
+    schema::{self, Flow, Format, Schema},
+    services::ServiceRegistry,
+    sessions::Sessions,
+    traffic::{Direction, Traffic, Volume},
+    zone::ZoneMatrix,
+};
+use anyhow::{anyhow, Result};
+use chrono::{FixedOffset, TimeDelta};
+use std::{
+    io::BufRead,
+    net::IpAddr,
+    sync::{mpsc, Mutex},
+};
+
+// bytes of input handed to a worker at a time.
+const CHUNK_SIZE: usize = 4 << 20;
+
+// lookup tables and settings shared while reading the input files.
+pub struct Context {
+    pub services: ServiceRegistry,
+    pub protocols: ProtocolTable,
+    pub local_net: IpMapper,
+    pub zones: IpMapper,
+    pub role: RoleConfig,
+    // sessions at least this long are listed as long-lived.
+    pub long_session: TimeDelta,
+}
+
+// everything counted from the flows of the inventory report.
+// each worker fills its own collector and they are merged at the end.
+#[derive(Debug)]
+pub struct Collector {
+    pub hosts: Hosts,
+    pub matrix: ZoneMatrix,
+    pub traffic: Traffic,
+    pub activity: Activity,
+    pub graph: Option<Graph>,
+    pub sightings: Sightings,
+    pub sessions: Sessions,
+}
+
+impl Collector {
+    // up to `top` long sessions are kept.
+    pub fn new(offset: FixedOffset, graph: bool, top: usize) -> Self {
+        Self {
+            hosts: Hosts::new(),
+            matrix: ZoneMatrix::new(),
+            traffic: Traffic::new(),
+            activity: Activity::new(offset),
+            graph: graph.then(Graph::new),
+            sightings: Sightings::new(),
+            sessions: Sessions::new(top),
+        }
+    }
+
+    // a collector with the same settings and nothing counted.
+    fn empty(&self) -> Self {
+        Self::new(
+            self.activity.offset(),
+            self.graph.is_some(),
+            self.sessions.top(),
+        )
+    }
+
+    pub fn merge(&mut self, other: Collector) {
+        self.hosts.merge(other.hosts);
+        self.matrix.merge(other.matrix);
+        self.traffic.merge(other.traffic);
+        self.activity.merge(other.activity);
+        if let (Some(graph), Some(other)) = (self.graph.as_mut(), other.graph) {
+            graph.merge(other);
+        }
+        self.sightings.merge(other.sightings);
+        self.sessions.merge(other.sessions);
+    }
+
+    pub fn insert(&mut self, ctx: &Context, flow: &Flow) {
+        let (Ok(src), Ok(dest)) = (flow.src.parse::<IpAddr>(), flow.dest.parse::<IpAddr>()) else {
+            return;
+        };
+        let src_is_local = ctx.local_net.lookup(src).is_some();
+        let dest_is_local = ctx.local_net.lookup(dest).is_some();
+        let bytes = flow.sent_bytes.saturating_add(flow.received_bytes);
+        self.matrix.insert(&ctx.zones, src, dest, bytes);
+        let Some(direction) = Direction::of(src_is_local, dest_is_local) else {
+            return;
+        };
+
+        let Some(proto_number) = ctx.protocols.number(flow.proto) else {
+            return;
+        };
+        let dest_port = if protocols::has_ports(proto_number) {
+            flow.dest_port
+        } else {
+            0
+        };
+        let timestamp = flow.time();
+
+        if let Some(graph) = self.graph.as_mut() {
+            graph.insert(
+                (src, src_is_local),
+                (dest, dest_is_local),
+                (dest_port, proto_number),
+                bytes,
+            );
+        }
+
+        let volume = Volume::session(
+            bytes,
+            flow.sent_packets.saturating_add(flow.received_packets),
+        );
+
+        if src_is_local {
+            self.hosts.insert(src, 0, proto_number);
+            self.traffic.insert_host(src, direction, volume);
+            if let Some(t) = &timestamp {
+                self.activity.insert_host(src, t);
+                self.sightings.insert_host(src, *t);
+            }
+        }
+
+        if dest_is_local {
+            self.hosts.insert_session(
+                dest,
+                dest_port,
+                proto_number,
+                src,
+                flow.sent_bytes,
+                flow.received_bytes,
+            );
+            if direction != Direction::Internal {
+                self.traffic.insert_host(dest, direction, volume);
+            }
+            let service = (dest, dest_port, proto_number);
+            if let Some(t) = &timestamp {
+                self.activity.insert_host(dest, t);
+                self.sightings.insert_host(dest, *t);
+                self.sightings.insert_service(service, flow.service, *t);
+                if dest_port != 0 {
+                    self.activity.insert_service(service, t);
+                }
+            }
+            if dest_port != 0 {
+                self.sessions.insert(
+                    (src, dest, dest_port, proto_number),
+                    (flow.sent_bytes, flow.received_bytes),
+                    (timestamp, flow.duration()),
+                    ctx.long_session,
+                );
+                self.traffic
+                    .insert_service(service, flow.service, direction, volume);
+            }
+        }
+    }
+
+    // count every record of the input.
+    fn insert_records<R: std::io::Read>(
+        &mut self,
+        ctx: &Context,
+        schema: &Schema,
+        rdr: csv::Reader<R>,
+    ) -> csv::Result<()> {
+        schema::for_each_record(rdr, |rec| {
+            if let Some(flow) = schema.flow(rec) {
+                self.insert(ctx, &flow);
+            }
+        })
+    }
+
+    // read a file ("-" for standard input) with `threads` workers.
+    // the input is split into chunks at line ends; each worker counts into its own
+    // collector and the results are merged into `self`.
+    pub fn read(
+        &mut self,
+        ctx: &Context,
+        format: Format,
+        path: &str,
+        threads: usize,
+    ) -> Result<()> {
+        let (schema, mut input) = Schema::open(format, path)?;
+        if threads <= 1 {
+            return self
+                .insert_records(ctx, &schema, format.reader(input))
+                .map_err(|e| anyhow!("fail to read {path}. {e}"));
+        }
+
+        let (tx, rx) = mpsc::sync_channel::<Vec<u8>>(threads * 2);
+        let rx = Mutex::new(rx);
+        let (read, collectors) = std::thread::scope(|s| {
+            let workers = (0..threads)
+                .map(|_| {
+                    let mut collector = self.empty();
+                    let (rx, schema) = (&rx, &schema);
+                    s.spawn(move || {
+                        loop {
+                            let chunk = match rx.lock() {
+                                Ok(rx) => rx.recv(),
+                                Err(_) => break,
+                            };
+                            let Ok(chunk) = chunk else {
+                                break;
+                            };
+                            collector.insert_records(
+                                ctx,
+                                schema,
+                                format.reader(chunk.as_slice()),
+                            )?;
+                        }
+                        Ok::<_, csv::Error>(collector)
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            let read = read_chunks(&mut input, &tx);
+            drop(tx);
+            let collectors = workers
+                .into_iter()
+                .map(|w| {
+                    w.join()
+                        .map_err(|_| anyhow!("worker panicked reading {path}"))?
+                        .map_err(|e| anyhow!("fail to parse {path}. {e}"))
+                })
+                .collect::<Result<Vec<_>>>();
+            (read, collectors)
+        });
+        for collector in collectors? {
+            self.merge(collector);
+        }
+        read.map_err(|e| anyhow!("fail to read {path}. {e}"))
+    }
+}
+
+fn read_chunks(input: &mut dyn BufRead, tx: &mpsc::SyncSender<Vec<u8>>) -> std::io::Result<()> {
+    while let Some(chunk) = input::read_chunk(input, CHUNK_SIZE)? {
+        if tx.send(chunk).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Collector, Context};
+    use crate::{
+        hosts::RoleConfig, net::IpMapper, protocols::ProtocolTable, schema::Format,
+        services::ServiceRegistry, zone::ZonePolicy,
+    };
+    use chrono::FixedOffset;
+    use std::fmt::Write;
+
+    #[test]
+    fn parallel_read_matches_sequential() {
+        let ctx = Context {
+            services: ServiceRegistry::new(),
+            protocols: ProtocolTable::builtin(),
+            local_net: ZonePolicy::with_local_networks(&["10.0.0.0/8"])
+                .local_networks()
+                .unwrap(),
+            zones: IpMapper::new(),
+            role: RoleConfig::default(),
+            long_session: chrono::TimeDelta::hours(1),
+        };
+        let mut log = String::new();
+        for i in 0..20_000u32 {
+            let client = if i % 3 == 0 {
+                "198.51.100.1"
+            } else {
+                "10.0.9.9"
+            };
+            writeln!(
+                log,
+                "{}\ts\t{client}\t40000\t10.0.{}.{}\t{}\t6\t0\t-\t10\t20\t1\t1",
+                1_700_000_000 + i,
+                i % 7,
+                i % 11,
+                [22, 443, 8080][i as usize % 3],
+            )
+            .unwrap();
+        }
+        let path =
+            std::env::temp_dir().join(format!("host-services-collect-{}.log", std::process::id()));
+        std::fs::write(&path, log).unwrap();
+        let path = path.to_string_lossy().to_string();
+
+        let offset = FixedOffset::east_opt(0).unwrap();
+        let mut one = Collector::new(offset, true, 10);
+        one.read(&ctx, Format::Giganto, &path, 1).unwrap();
+        let mut four = Collector::new(offset, true, 10);
+        four.read(&ctx, Format::Giganto, &path, 4).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(one.hosts.hosts(), four.hosts.hosts());
+        assert_eq!(
+            one.hosts.servers(&ctx.role, &ctx.protocols),
+            four.hosts.servers(&ctx.role, &ctx.protocols)
+        );
+        assert_eq!(one.hosts.hosts().len(), 78);
+        let json = |c: &Collector| serde_json::to_string(&c.hosts).unwrap();
+        assert_eq!(json(&one), json(&four));
+    }
+}
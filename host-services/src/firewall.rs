@@ -0,0 +1,614 @@
+use crate::{hosts::ServerRole, protocols::ProtocolTable};
+use anyhow::{anyhow, Result};
+use ipnet::IpNet;
+use regex::Regex;
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::IpAddr,
+    ops::RangeInclusive,
+    path::Path,
+};
+
+// marker of the rule lines in /etc/ufw/user.rules and user6.rules.
+const TUPLE_MARKER: &str = "### tuple ###";
+
+// ports and protocol of one part of an application profile. `None` for any protocol.
+type PortSpec = (Vec<RangeInclusive<u16>>, Option<u8>);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+    Allow,
+    Deny,
+    Reject,
+    Limit,
+}
+
+impl Action {
+    // limit rules accept connections up to the rate limit.
+    pub fn permits(self) -> bool {
+        matches!(self, Action::Allow | Action::Limit)
+    }
+}
+
+impl std::str::FromStr for Action {
+    type Err = String;
+
+    // "ALLOW", or "allow_log" as written in user.rules.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let action = s.split('_').next().unwrap_or(s);
+        match action.to_lowercase().as_str() {
+            "allow" => Ok(Action::Allow),
+            "deny" => Ok(Action::Deny),
+            "reject" => Ok(Action::Reject),
+            "limit" => Ok(Action::Limit),
+            _ => Err(format!("unknown ufw action {s}")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RuleDirection {
+    In,
+    Out,
+    // routed rules
+    Fwd,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    // rule number as `ufw status numbered` shows it
+    pub number: usize,
+    pub action: Action,
+    pub direction: RuleDirection,
+    pub to: IpNet,
+    // `None` for any port
+    pub ports: Option<Vec<RangeInclusive<u16>>>,
+    // `None` for any protocol
+    pub proto: Option<u8>,
+    pub from: IpNet,
+    // application profile whose ports are not known from the status output
+    pub app: Option<String>,
+    // ports of the application profile, once resolved
+    pub app_ports: Option<Vec<PortSpec>>,
+    pub text: String,
+}
+
+impl Rule {
+    fn matches(&self, src: IpAddr, dest: IpAddr, port: u16, proto: u8) -> bool {
+        let ports = |ports: &Vec<RangeInclusive<u16>>| ports.iter().any(|r| r.contains(&port));
+        let service = match (&self.app, &self.app_ports) {
+            (None, _) => {
+                self.proto.is_none_or(|p| p == proto) && self.ports.as_ref().is_none_or(ports)
+            }
+            (Some(_), Some(specs)) => specs
+                .iter()
+                .any(|(p, pr)| pr.is_none_or(|pr| pr == proto) && ports(p)),
+            (Some(_), None) => false,
+        };
+        service && self.addresses(src, dest)
+    }
+
+    fn addresses(&self, src: IpAddr, dest: IpAddr) -> bool {
+        self.to.contains(&dest) && self.from.contains(&src)
+    }
+
+    // an application profile rule whose ports are not known.
+    pub fn unresolved(&self) -> bool {
+        self.app.is_some() && self.app_ports.is_none()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Ruleset {
+    rules: Vec<Rule>,
+}
+
+impl Ruleset {
+    pub fn from_path(path: &str, protocols: &ProtocolTable) -> Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        Self::parse(&text, protocols)
+    }
+
+    // `ufw status numbered` (or `ufw status`) output, or the tuple comments of user.rules.
+    // header lines and other lines that are not rules are skipped.
+    pub fn parse(text: &str, protocols: &ProtocolTable) -> Result<Self> {
+        let status = Regex::new(
+            r"^\s*(?:\[\s*(\d+)\]\s*)?(.+?)\s+(ALLOW|DENY|REJECT|LIMIT)(?:\s+(IN|OUT|FWD))?\s+(.+?)\s*(?:#.*)?$",
+        )?;
+        let mut rules = Vec::new();
+        for (idx, line) in text.lines().enumerate() {
+            let number = rules.len() + 1;
+            let rule = if let Some(tuple) = line.split_once(TUPLE_MARKER).map(|(_, t)| t) {
+                parse_tuple(number, tuple, protocols)
+            } else if let Some(caps) = status.captures(line) {
+                let number = caps
+                    .get(1)
+                    .and_then(|n| n.as_str().parse().ok())
+                    .unwrap_or(number);
+                parse_status(number, &caps, protocols)
+            } else {
+                continue;
+            };
+            let mut rule = rule
+                .ok_or_else(|| anyhow!("invalid ufw rule at line {}: {}", idx + 1, line.trim()))?;
+            rule.text = line.trim().to_string();
+            rules.push(rule);
+        }
+        Ok(Self { rules })
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    // fill in the ports of application profile rules from the profiles in `dir`, such as
+    // /etc/ufw/applications.d. profiles not found there stay unresolved.
+    pub fn resolve_apps(&mut self, dir: &Path, protocols: &ProtocolTable) -> Result<()> {
+        if !dir.is_dir() {
+            return Ok(());
+        }
+        let mut profiles = HashMap::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if !path.is_file() {
+                continue;
+            }
+            let text = std::fs::read_to_string(&path)
+                .map_err(|e| anyhow!("fail to read {}. {e}", path.display()))?;
+            profiles.extend(parse_profiles(&text, protocols));
+        }
+        for rule in &mut self.rules {
+            if let Some(app) = &rule.app {
+                rule.app_ports = profiles.get(app).cloned();
+            }
+        }
+        Ok(())
+    }
+}
+
+// the ports of each profile in an application profile file:
+//   [OpenSSH]
+//   title=Secure shell server
+//   ports=22/tcp
+// "ports" lists parts separated by "|", e.g. "80,443/tcp" or "53|60000:61000/udp".
+// a part without a protocol is for tcp and udp. profiles with invalid ports are left out.
+fn parse_profiles(text: &str, protocols: &ProtocolTable) -> HashMap<String, Vec<PortSpec>> {
+    let mut profiles = HashMap::new();
+    let mut name = None;
+    for line in text.lines().map(str::trim) {
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            name = Some(section.trim().to_string());
+        } else if let (Some(name), Some(ports)) = (&name, line.strip_prefix("ports=")) {
+            let specs = ports
+                .split('|')
+                .map(|part| match part.split_once('/') {
+                    Some((ports, proto)) => {
+                        Some((parse_ports(ports)?, Some(protocols.number(proto)?)))
+                    }
+                    None => Some((parse_ports(part)?, None)),
+                })
+                .collect::<Option<Vec<_>>>();
+            if let Some(specs) = specs {
+                profiles.insert(name.clone(), specs);
+            }
+        }
+    }
+    profiles
+}
+
+fn any_address(v6: bool) -> IpNet {
+    if v6 {
+        IpNet::V6(ipnet::Ipv6Net::default())
+    } else {
+        IpNet::V4(ipnet::Ipv4Net::default())
+    }
+}
+
+// "10.0.0.5", "10.0.0.0/8" or "Anywhere"
+fn parse_address(s: &str, v6: bool) -> Option<IpNet> {
+    if s.eq_ignore_ascii_case("anywhere") || s == "any" {
+        return Some(any_address(v6));
+    }
+    s.parse::<IpNet>()
+        .ok()
+        .or_else(|| s.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+// "22", "80,443" or "6000:6007"
+fn parse_ports(s: &str) -> Option<Vec<RangeInclusive<u16>>> {
+    s.split(',')
+        .map(|p| match p.split_once(':') {
+            Some((start, end)) => {
+                let (start, end) = (start.parse::<u16>().ok()?, end.parse::<u16>().ok()?);
+                (start <= end).then_some(start..=end)
+            }
+            None => p.parse::<u16>().ok().map(|p| p..=p),
+        })
+        .collect()
+}
+
+// address, ports and protocol of one side of a status line.
+// "Anywhere", "22/tcp", "10.0.0.5 80,443/tcp", "Anywhere on eth0", "OpenSSH (v6)"
+struct Endpoint {
+    addr: Option<IpNet>,
+    ports: Option<Vec<RangeInclusive<u16>>>,
+    proto: Option<u8>,
+    app: Option<String>,
+    v6: bool,
+}
+
+fn parse_endpoint(s: &str, protocols: &ProtocolTable) -> Option<Endpoint> {
+    let v6 = s.contains("(v6)");
+    let s = s.replace("(v6)", "");
+    let s = s.split(" on ").next().unwrap_or_default().trim();
+    let mut end = Endpoint {
+        addr: None,
+        ports: None,
+        proto: None,
+        app: None,
+        v6,
+    };
+    let (first, rest) = s.split_once(char::is_whitespace).unwrap_or((s, ""));
+    // "Anywhere" is left as `None` until the address family of the rule is known.
+    let service = if first.eq_ignore_ascii_case("anywhere") {
+        rest.trim()
+    } else if let Some(addr) = parse_address(first, v6) {
+        end.addr = Some(addr);
+        rest.trim()
+    } else {
+        s
+    };
+    if service.is_empty() {
+        return Some(end);
+    }
+    let (ports, proto) = match service.split_once('/') {
+        Some((ports, proto)) => (ports, Some(proto)),
+        None => (service, None),
+    };
+    match (parse_ports(ports), proto) {
+        (Some(ports), proto) => {
+            end.ports = Some(ports);
+            end.proto = match proto {
+                Some(p) => Some(protocols.number(p)?),
+                None => None,
+            };
+        }
+        (None, _) => end.app = Some(service.to_string()),
+    }
+    Some(end)
+}
+
+fn parse_status(number: usize, caps: &regex::Captures, protocols: &ProtocolTable) -> Option<Rule> {
+    let to = parse_endpoint(&caps[2], protocols)?;
+    let from = parse_endpoint(&caps[5], protocols)?;
+    let v6 = to.v6
+        || from.v6
+        || [to.addr, from.addr]
+            .iter()
+            .any(|a| matches!(a, Some(IpNet::V6(_))));
+    let direction = match caps.get(4).map(|d| d.as_str()) {
+        Some("OUT") => RuleDirection::Out,
+        Some("FWD") => RuleDirection::Fwd,
+        _ => RuleDirection::In,
+    };
+    Some(Rule {
+        number,
+        action: caps[3].parse().ok()?,
+        direction,
+        to: to.addr.unwrap_or(any_address(v6)),
+        ports: to.ports,
+        proto: to.proto,
+        from: from.addr.unwrap_or(any_address(v6)),
+        app: to.app,
+        app_ports: None,
+        text: String::new(),
+    })
+}
+
+// action proto dport dst sport src [dapp sapp] direction [comment=...]
+// e.g. "allow tcp 22 0.0.0.0/0 any 0.0.0.0/0 in", "route:deny any any ::/0 any ::/0 in_eth0!out_eth1"
+fn parse_tuple(number: usize, tuple: &str, protocols: &ProtocolTable) -> Option<Rule> {
+    let fields = tuple
+        .split_whitespace()
+        .filter(|f| !f.starts_with("comment="))
+        .collect::<Vec<_>>();
+    let direction = match fields.len() {
+        7 => fields[6],
+        9 => fields[8],
+        _ => return None,
+    };
+    let (action, proto, dport, dst, src) = (fields[0], fields[1], fields[2], fields[3], fields[5]);
+    let (route, action) = match action.strip_prefix("route:") {
+        Some(action) => (true, action),
+        None => (false, action),
+    };
+    let direction = if route {
+        RuleDirection::Fwd
+    } else if direction.starts_with("out") {
+        RuleDirection::Out
+    } else {
+        RuleDirection::In
+    };
+    Some(Rule {
+        number,
+        action: action.parse().ok()?,
+        direction,
+        to: parse_address(dst, false)?,
+        ports: match dport {
+            "any" => None,
+            ports => Some(parse_ports(ports)?),
+        },
+        proto: match proto {
+            "any" => None,
+            proto => Some(protocols.number(proto)?),
+        },
+        from: parse_address(src, false)?,
+        app: None,
+        app_ports: None,
+        text: String::new(),
+    })
+}
+
+// sessions to a local service and the rules that decided them.
+#[derive(Debug, Default)]
+struct Decisions {
+    sessions: u64,
+    permitted: u64,
+    // sessions first matched by an application profile rule of unknown ports
+    undecided: u64,
+    // numbers of the deny rules that matched. empty if the default policy applied.
+    denied_by: BTreeSet<usize>,
+}
+
+// per-session evaluation of a ufw rule set.
+#[derive(Debug)]
+pub struct Compliance {
+    rules: Ruleset,
+    // sessions decided by each rule, in the order of the rules
+    hits: Vec<u64>,
+    services: HashMap<(IpAddr, u16, u8), Decisions>,
+}
+
+#[derive(Debug)]
+pub struct Unpermitted<'a> {
+    pub server: &'a ServerRole,
+    pub sessions: u64,
+    pub denied_by: Vec<usize>,
+}
+
+impl Compliance {
+    pub fn new(rules: Ruleset) -> Self {
+        Self {
+            hits: vec![0; rules.rules.len()],
+            rules,
+            services: HashMap::new(),
+        }
+    }
+
+    // an incoming session to a host the rules protect.
+    pub fn insert(&mut self, src: IpAddr, dest: IpAddr, port: u16, proto: u8) {
+        let decisions = self.services.entry((dest, port, proto)).or_default();
+        decisions.sessions += 1;
+        // the first matching incoming rule decides, as in ufw.
+        // without one the default policy applies.
+        let Some(idx) = self.rules.rules.iter().position(|r| {
+            r.direction == RuleDirection::In
+                && (r.matches(src, dest, port, proto) || r.unresolved() && r.addresses(src, dest))
+        }) else {
+            return;
+        };
+        if self.rules.rules[idx].unresolved() {
+            decisions.undecided += 1;
+            return;
+        }
+        self.hits[idx] += 1;
+        let rule = &self.rules.rules[idx];
+        if rule.action.permits() {
+            decisions.permitted += 1;
+        } else {
+            decisions.denied_by.insert(rule.number);
+        }
+    }
+
+    // servers whose sessions no allow rule permits. servers with sessions that an
+    // application profile rule of unknown ports may decide are left out.
+    pub fn unpermitted<'a>(&self, servers: &'a [ServerRole]) -> Vec<Unpermitted<'a>> {
+        servers
+            .iter()
+            .filter_map(|server| {
+                let decisions = self.decisions(server)?;
+                (decisions.permitted == 0 && decisions.undecided == 0).then(|| Unpermitted {
+                    server,
+                    sessions: decisions.sessions,
+                    denied_by: decisions.denied_by.iter().copied().collect(),
+                })
+            })
+            .collect()
+    }
+
+    // servers left out of `unpermitted` for application profile rules of unknown ports.
+    pub fn undecided<'a>(&self, servers: &'a [ServerRole]) -> Vec<&'a ServerRole> {
+        servers
+            .iter()
+            .filter(|server| {
+                self.decisions(server)
+                    .is_some_and(|d| d.permitted == 0 && d.undecided > 0)
+            })
+            .collect()
+    }
+
+    fn decisions(&self, server: &ServerRole) -> Option<&Decisions> {
+        let host = server.host.parse::<IpAddr>().ok()?;
+        self.services.get(&(host, server.port, server.proto.number))
+    }
+
+    // incoming allow rules that no session matched.
+    pub fn unused(&self) -> Vec<&Rule> {
+        self.rules
+            .rules
+            .iter()
+            .zip(&self.hits)
+            .filter(|(r, hits)| {
+                r.direction == RuleDirection::In && r.action.permits() && **hits == 0
+            })
+            .map(|(r, _)| r)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_profiles, Action, Compliance, RuleDirection, Ruleset};
+    use crate::{hosts::ServerRole, protocols::ProtocolTable};
+    use std::net::IpAddr;
+
+    const STATUS: &str = "Status: active
+
+     To                         Action      From
+     --                         ------      ----
+[ 1] 22/tcp                     ALLOW IN    Anywhere
+[ 2] 80,443/tcp                 ALLOW IN    Anywhere                   # web
+[ 3] 6000:6007/udp              LIMIT IN    10.0.0.0/8
+[ 4] 10.0.0.5 5432/tcp          ALLOW IN    10.0.1.0/24
+[ 5] OpenSSH                    ALLOW IN    Anywhere
+[ 6] Anywhere                   DENY IN     203.0.113.100
+[ 7] 25                         DENY IN     Anywhere
+[ 8] Anywhere on eth0           ALLOW IN    203.0.113.102
+[ 9] 53                         ALLOW OUT   Anywhere
+[10] 22/tcp (v6)                ALLOW IN    Anywhere (v6)
+";
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn status_numbered() {
+        let rules = Ruleset::parse(STATUS, &ProtocolTable::builtin()).unwrap();
+        let rules = rules.rules();
+        assert_eq!(rules.len(), 10);
+        assert_eq!(rules[1].ports, Some(vec![80..=80, 443..=443]));
+        assert_eq!(rules[1].proto, Some(6));
+        assert_eq!(rules[2].action, Action::Limit);
+        assert_eq!(rules[2].ports, Some(vec![6000..=6007]));
+        assert_eq!(rules[2].from, "10.0.0.0/8".parse().unwrap());
+        assert_eq!(rules[3].to, "10.0.0.5/32".parse().unwrap());
+        assert_eq!(rules[4].app.as_deref(), Some("OpenSSH"));
+        assert_eq!(rules[5].ports, None);
+        assert_eq!(rules[6].proto, None);
+        assert_eq!(rules[8].direction, RuleDirection::Out);
+        assert_eq!(rules[9].to, "::/0".parse().unwrap());
+        assert_eq!(rules[9].number, 10);
+    }
+
+    #[test]
+    fn user_rules_tuples() {
+        let text = "*filter
+### tuple ### allow tcp 22 0.0.0.0/0 any 0.0.0.0/0 in
+-A ufw-user-input -p tcp --dport 22 -j ACCEPT
+### tuple ### allow_log tcp 80,443 0.0.0.0/0 any 10.0.0.0/8 Apache%20Full - in comment=7765620a
+### tuple ### deny any any 0.0.0.0/0 any 203.0.113.100 out
+### tuple ### route:allow udp 53 0.0.0.0/0 any 0.0.0.0/0 in_eth0!out_eth1
+";
+        let rules = Ruleset::parse(text, &ProtocolTable::builtin()).unwrap();
+        let rules = rules.rules();
+        assert_eq!(rules.len(), 4);
+        assert_eq!(rules[0].ports, Some(vec![22..=22]));
+        assert_eq!(rules[1].from, "10.0.0.0/8".parse().unwrap());
+        assert_eq!(rules[1].action, Action::Allow);
+        assert_eq!(rules[2].direction, RuleDirection::Out);
+        assert_eq!(rules[3].direction, RuleDirection::Fwd);
+        assert_eq!(rules[3].number, 4);
+    }
+
+    #[test]
+    fn compliance() {
+        let protocols = ProtocolTable::builtin();
+        let server = |host: &str, port| ServerRole {
+            host: host.to_string(),
+            port,
+            proto: protocols.protocol(6),
+            confidence: 1.0,
+            reasons: Vec::new(),
+        };
+        let servers = [5432, 25, 3306, 22, 8080].map(|port| server("10.0.0.5", port));
+        let sessions = |compliance: &mut Compliance| {
+            // permitted by rule 1
+            compliance.insert(ip("198.51.100.1"), ip("10.0.0.5"), 22, 6);
+            // rule 4 only allows 10.0.1.0/24
+            compliance.insert(ip("10.0.2.9"), ip("10.0.0.5"), 5432, 6);
+            // denied by rule 7
+            compliance.insert(ip("198.51.100.1"), ip("10.0.0.5"), 25, 6);
+            // the source is denied by rule 6
+            compliance.insert(ip("203.0.113.100"), ip("10.0.0.5"), 3306, 6);
+            compliance.insert(ip("198.51.100.1"), ip("10.0.0.5"), 8080, 6);
+        };
+
+        // rule 5 may allow any of them while the ports of OpenSSH are unknown
+        let mut compliance = Compliance::new(Ruleset::parse(STATUS, &protocols).unwrap());
+        sessions(&mut compliance);
+        assert!(compliance.unpermitted(&servers).is_empty());
+        let undecided = compliance
+            .undecided(&servers)
+            .iter()
+            .map(|s| s.port)
+            .collect::<Vec<_>>();
+        assert_eq!(undecided, vec![5432, 25, 3306, 8080]);
+
+        let dir = std::env::temp_dir().join(format!("ufw-apps-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("openssh-server"), "[OpenSSH]\nports=22/tcp\n").unwrap();
+        let mut rules = Ruleset::parse(STATUS, &protocols).unwrap();
+        rules.resolve_apps(&dir, &protocols).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(!rules.rules()[4].unresolved());
+        let mut compliance = Compliance::new(rules);
+        sessions(&mut compliance);
+        assert!(compliance.undecided(&servers).is_empty());
+        let unpermitted = compliance.unpermitted(&servers);
+        let found = unpermitted
+            .iter()
+            .map(|u| (u.server.port, u.denied_by.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            vec![
+                (5432, vec![]),
+                (25, vec![7]),
+                (3306, vec![6]),
+                (8080, vec![])
+            ]
+        );
+
+        let unused = compliance
+            .unused()
+            .iter()
+            .map(|r| r.number)
+            .collect::<Vec<_>>();
+        assert_eq!(unused, vec![2, 3, 4, 5, 8, 10]);
+    }
+
+    #[test]
+    fn application_profiles() {
+        let text = "[Apache Full]
+title=Web Server (HTTP,HTTPS)
+ports=80,443/tcp
+
+[Bind9]
+ports=53|60000:61000/udp
+
+[Broken]
+ports=http/tcp
+";
+        let profiles = parse_profiles(text, &ProtocolTable::builtin());
+        assert_eq!(
+            profiles["Apache Full"],
+            vec![(vec![80..=80, 443..=443], Some(6))]
+        );
+        assert_eq!(
+            profiles["Bind9"],
+            vec![(vec![53..=53], None), (vec![60000..=61000], Some(17))]
+        );
+        assert!(!profiles.contains_key("Broken"));
+    }
+}
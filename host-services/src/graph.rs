@@ -0,0 +1,359 @@
+use crate::protocols::{Protocol, ProtocolTable};
+use anyhow::Result;
+use serde::Serialize;
+use std::{collections::BTreeMap, io::Write, net::IpAddr};
+
+// node colors by local network classification.
+const INTERNAL_COLOR: (u8, u8, u8) = (0x4e, 0x79, 0xa7);
+const EXTERNAL_COLOR: (u8, u8, u8) = (0xe1, 0x57, 0x59);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GraphFormat {
+    Dot,
+    Gexf,
+    Json,
+}
+
+impl std::str::FromStr for GraphFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "dot" => Ok(GraphFormat::Dot),
+            "gexf" => Ok(GraphFormat::Gexf),
+            "json" => Ok(GraphFormat::Json),
+            _ => Err(format!("unknown graph format {s}")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeKind {
+    Internal,
+    External,
+}
+
+impl NodeKind {
+    fn color(self) -> (u8, u8, u8) {
+        match self {
+            NodeKind::Internal => INTERNAL_COLOR,
+            NodeKind::External => EXTERNAL_COLOR,
+        }
+    }
+
+    fn hex_color(self) -> String {
+        let (r, g, b) = self.color();
+        format!("#{r:02x}{g:02x}{b:02x}")
+    }
+}
+
+impl std::fmt::Display for NodeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NodeKind::Internal => write!(f, "internal"),
+            NodeKind::External => write!(f, "external"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+struct Weight {
+    sessions: u64,
+    bytes: u64,
+}
+
+// directed host-to-host graph. parallel edges are kept apart by service.
+// hosts and protocols are named when the graph is written.
+#[derive(Debug, Default)]
+pub struct Graph {
+    nodes: BTreeMap<IpAddr, NodeKind>,
+    // (source, destination, port, protocol number) => weight. port is 0 for port-less protocols.
+    edges: BTreeMap<(IpAddr, IpAddr, u16, u8), Weight>,
+}
+
+#[derive(Serialize)]
+struct NodeLink {
+    directed: bool,
+    multigraph: bool,
+    nodes: Vec<JsonNode>,
+    links: Vec<JsonLink>,
+}
+
+#[derive(Serialize)]
+struct JsonNode {
+    id: String,
+    kind: NodeKind,
+    color: String,
+}
+
+#[derive(Serialize)]
+struct JsonLink {
+    source: String,
+    target: String,
+    service: String,
+    port: u16,
+    proto: String,
+    sessions: u64,
+    bytes: u64,
+}
+
+impl Graph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // `src` and `dest` are (host, whether the host is in the local networks).
+    pub fn insert(
+        &mut self,
+        (src, src_is_local): (IpAddr, bool),
+        (dest, dest_is_local): (IpAddr, bool),
+        (port, proto): (u16, u8),
+        bytes: u64,
+    ) {
+        for (host, is_local) in [(src, src_is_local), (dest, dest_is_local)] {
+            self.nodes.entry(host).or_insert(if is_local {
+                NodeKind::Internal
+            } else {
+                NodeKind::External
+            });
+        }
+        let weight = self.edges.entry((src, dest, port, proto)).or_default();
+        weight.sessions += 1;
+        weight.bytes = weight.bytes.saturating_add(bytes);
+    }
+
+    pub fn merge(&mut self, other: Graph) {
+        for (host, kind) in other.nodes {
+            self.nodes.entry(host).or_insert(kind);
+        }
+        for (key, weight) in other.edges {
+            let w = self.edges.entry(key).or_default();
+            w.sessions += weight.sessions;
+            w.bytes = w.bytes.saturating_add(weight.bytes);
+        }
+    }
+
+    pub fn write<W: Write>(
+        &self,
+        format: GraphFormat,
+        w: &mut W,
+        protocols: &ProtocolTable,
+    ) -> Result<()> {
+        match format {
+            GraphFormat::Dot => self.write_dot(w, protocols),
+            GraphFormat::Gexf => self.write_gexf(w, protocols),
+            GraphFormat::Json => {
+                serde_json::to_writer_pretty(&mut *w, &self.node_link(protocols))?;
+                writeln!(w)?;
+                Ok(())
+            }
+        }
+    }
+
+    fn write_dot<W: Write>(&self, w: &mut W, protocols: &ProtocolTable) -> Result<()> {
+        writeln!(w, "digraph hosts {{")?;
+        writeln!(w, "  node [shape=box, style=filled, fontcolor=white];")?;
+        for (host, kind) in &self.nodes {
+            writeln!(
+                w,
+                "  \"{host}\" [kind={kind}, fillcolor=\"{}\"];",
+                kind.hex_color()
+            )?;
+        }
+        for ((src, dest, port, proto), weight) in &self.edges {
+            writeln!(
+                w,
+                "  \"{src}\" -> \"{dest}\" [label=\"{}\", sessions={}, bytes={}];",
+                service_label(*port, &protocols.protocol(*proto)),
+                weight.sessions,
+                weight.bytes
+            )?;
+        }
+        writeln!(w, "}}")?;
+        Ok(())
+    }
+
+    fn write_gexf<W: Write>(&self, w: &mut W, protocols: &ProtocolTable) -> Result<()> {
+        writeln!(w, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(
+            w,
+            r#"<gexf xmlns="http://gexf.net/1.3" xmlns:viz="http://gexf.net/1.3/viz" version="1.3">"#
+        )?;
+        writeln!(w, r#"  <graph defaultedgetype="directed" mode="static">"#)?;
+        writeln!(w, r#"    <attributes class="node">"#)?;
+        writeln!(
+            w,
+            r#"      <attribute id="kind" title="kind" type="string"/>"#
+        )?;
+        writeln!(w, "    </attributes>")?;
+        writeln!(w, r#"    <attributes class="edge">"#)?;
+        writeln!(
+            w,
+            r#"      <attribute id="service" title="service" type="string"/>"#
+        )?;
+        writeln!(
+            w,
+            r#"      <attribute id="sessions" title="sessions" type="long"/>"#
+        )?;
+        writeln!(
+            w,
+            r#"      <attribute id="bytes" title="bytes" type="long"/>"#
+        )?;
+        writeln!(w, "    </attributes>")?;
+
+        writeln!(w, "    <nodes>")?;
+        for (host, kind) in &self.nodes {
+            let (r, g, b) = kind.color();
+            writeln!(w, r#"      <node id="{host}" label="{host}">"#)?;
+            writeln!(
+                w,
+                r#"        <attvalues><attvalue for="kind" value="{kind}"/></attvalues>"#
+            )?;
+            writeln!(w, r#"        <viz:color r="{r}" g="{g}" b="{b}"/>"#)?;
+            writeln!(w, "      </node>")?;
+        }
+        writeln!(w, "    </nodes>")?;
+
+        writeln!(w, "    <edges>")?;
+        for (id, ((src, dest, port, proto), weight)) in self.edges.iter().enumerate() {
+            let service = xml_escape(&service_label(*port, &protocols.protocol(*proto)));
+            writeln!(
+                w,
+                r#"      <edge id="{id}" source="{src}" target="{dest}" label="{service}" weight="{}">"#,
+                weight.sessions
+            )?;
+            writeln!(w, "        <attvalues>")?;
+            writeln!(
+                w,
+                r#"          <attvalue for="service" value="{service}"/>"#
+            )?;
+            writeln!(
+                w,
+                r#"          <attvalue for="sessions" value="{}"/>"#,
+                weight.sessions
+            )?;
+            writeln!(
+                w,
+                r#"          <attvalue for="bytes" value="{}"/>"#,
+                weight.bytes
+            )?;
+            writeln!(w, "        </attvalues>")?;
+            writeln!(w, "      </edge>")?;
+        }
+        writeln!(w, "    </edges>")?;
+        writeln!(w, "  </graph>")?;
+        writeln!(w, "</gexf>")?;
+        Ok(())
+    }
+
+    // node-link layout used by networkx and d3.
+    fn node_link(&self, protocols: &ProtocolTable) -> NodeLink {
+        NodeLink {
+            directed: true,
+            multigraph: true,
+            nodes: self
+                .nodes
+                .iter()
+                .map(|(host, kind)| JsonNode {
+                    id: host.to_string(),
+                    kind: *kind,
+                    color: kind.hex_color(),
+                })
+                .collect(),
+            links: self
+                .edges
+                .iter()
+                .map(|((src, dest, port, proto), weight)| {
+                    let proto = protocols.protocol(*proto);
+                    JsonLink {
+                        source: src.to_string(),
+                        target: dest.to_string(),
+                        service: service_label(*port, &proto),
+                        port: *port,
+                        proto: proto.name,
+                        sessions: weight.sessions,
+                        bytes: weight.bytes,
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+// "443/tcp", or the protocol name alone for port-less protocols.
+fn service_label(port: u16, proto: &Protocol) -> String {
+    if port == 0 {
+        proto.name.clone()
+    } else {
+        format!("{port}/{proto}")
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Graph, GraphFormat, NodeKind};
+    use crate::protocols::ProtocolTable;
+    use std::net::IpAddr;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn graph() -> Graph {
+        let mut graph = Graph::new();
+        let (a, b) = ((ip("10.0.0.1"), true), (ip("10.0.0.2"), true));
+        graph.insert(a, b, (443, 6), 100);
+        graph.insert(a, b, (443, 6), 50);
+        graph.insert(a, b, (0, 1), 64);
+        graph.insert((ip("198.51.100.7"), false), b, (22, 6), 10);
+        graph
+    }
+
+    fn export(graph: &Graph, format: GraphFormat) -> String {
+        let mut buf = Vec::new();
+        graph
+            .write(format, &mut buf, &ProtocolTable::builtin())
+            .unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn weighted_edges_per_service() {
+        let graph = graph();
+        assert_eq!(graph.nodes.len(), 3);
+        assert_eq!(graph.nodes[&ip("198.51.100.7")], NodeKind::External);
+        assert_eq!(graph.edges.len(), 3);
+
+        let json: serde_json::Value =
+            serde_json::from_str(&export(&graph, GraphFormat::Json)).unwrap();
+        let links = json["links"].as_array().unwrap();
+        let https = links.iter().find(|l| l["service"] == "443/tcp").unwrap();
+        assert_eq!(https["sessions"], 2);
+        assert_eq!(https["bytes"], 150);
+        assert!(links.iter().any(|l| l["service"] == "icmp"));
+        assert_eq!(json["nodes"][0]["kind"], "internal");
+    }
+
+    #[test]
+    fn dot_and_gexf() {
+        let graph = graph();
+        let dot = export(&graph, GraphFormat::Dot);
+        assert!(dot.starts_with("digraph hosts {"));
+        assert!(dot
+            .contains("\"10.0.0.1\" -> \"10.0.0.2\" [label=\"443/tcp\", sessions=2, bytes=150];"));
+        assert!(dot.contains("\"198.51.100.7\" [kind=external"));
+
+        let gexf = export(&graph, GraphFormat::Gexf);
+        assert_eq!(gexf.matches("<node ").count(), 3);
+        assert_eq!(gexf.matches("<edge ").count(), 3);
+        assert!(gexf.contains(r#"<viz:color r="225" g="87" b="89"/>"#));
+    }
+}
@@ -1,40 +1,323 @@
-use std::collections::HashMap;
+use crate::protocols::{self, Protocol, ProtocolTable};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    ops::RangeInclusive,
+};
 
+// weights of each evidence in the server confidence score. the sum is 1.0.
+const WEIGHT_CLIENTS: f64 = 0.35;
+const WEIGHT_SESSIONS: f64 = 0.2;
+const WEIGHT_BYTES_RATIO: f64 = 0.2;
+const WEIGHT_FIXED_PORT: f64 = 0.25;
+
+// distinct clients and sessions at which each evidence is fully counted.
+const FULL_CLIENTS: usize = 5;
+const FULL_SESSIONS: u64 = 10;
+
+// (host, port, protocol number) => observation.
+// keys are not allocated per flow; protocol names are resolved when reporting.
+#[derive(Debug, Default)]
 pub struct Hosts {
-    hosts: HashMap<(String, u16, String), u32>,
+    hosts: HashMap<(IpAddr, u16, u8), Observation>,
 }
 
-impl Hosts {
-    pub fn new() -> Self {
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Observation {
+    sessions: u64,
+    clients: HashSet<IpAddr>,
+    // bytes sent by the clients and by the observed host.
+    client_bytes: u64,
+    server_bytes: u64,
+}
+
+// an observation with its key, as saved in state files.
+#[derive(Deserialize)]
+struct Record {
+    host: IpAddr,
+    port: u16,
+    proto: u8,
+    #[serde(flatten)]
+    obs: Observation,
+}
+
+#[derive(Serialize)]
+struct RecordRef<'a> {
+    host: IpAddr,
+    port: u16,
+    proto: u8,
+    #[serde(flatten)]
+    obs: &'a Observation,
+}
+
+#[derive(Clone, Debug)]
+pub struct RoleConfig {
+    pub threshold: f64,
+    pub ephemeral_ports: RangeInclusive<u16>,
+}
+
+impl Default for RoleConfig {
+    fn default() -> Self {
         Self {
-            hosts: HashMap::new(),
+            threshold: 0.6,
+            ephemeral_ports: 32768..=65535,
         }
     }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerRole {
+    pub host: String,
+    pub port: u16,
+    pub proto: Protocol,
+    pub confidence: f64,
+    pub reasons: Vec<String>,
+}
+
+impl Hosts {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-    pub fn insert(&mut self, host: &str, port: u16, proto: &str) {
-        let key = (host.to_string(), port, proto.to_string());
-        self.hosts
-            .entry(key)
-            .and_modify(|cnt| *cnt += 1)
-            .or_insert(1);
+    pub fn insert(&mut self, host: IpAddr, port: u16, proto: u8) {
+        self.hosts.entry((host, port, proto)).or_default().sessions += 1;
+    }
+
+    // record a session that a client opened to host:port.
+    // `sent` and `received` are the bytes sent and received by the client.
+    // port is 0 for protocols without ports such as icmp or esp.
+    pub fn insert_session(
+        &mut self,
+        host: IpAddr,
+        port: u16,
+        proto: u8,
+        client: IpAddr,
+        sent: u64,
+        received: u64,
+    ) {
+        let obs = self.hosts.entry((host, port, proto)).or_default();
+        obs.sessions += 1;
+        obs.clients.insert(client);
+        obs.client_bytes = obs.client_bytes.saturating_add(sent);
+        obs.server_bytes = obs.server_bytes.saturating_add(received);
+    }
+
+    pub fn servers(&self, conf: &RoleConfig, protocols: &ProtocolTable) -> Vec<ServerRole> {
+        let mut servers = self
+            .hosts
+            .iter()
+            .filter(|((_, port, _), _)| *port != 0)
+            .map(|((host, port, proto), obs)| {
+                let (confidence, reasons) = obs.server_score(*port, conf);
+                (host, port, proto, confidence, reasons)
+            })
+            .filter(|s| s.3 >= conf.threshold)
+            .collect::<Vec<_>>();
+        servers.sort_by_key(|s| (*s.0, *s.1, *s.2));
+        servers
+            .into_iter()
+            .map(|(host, port, proto, confidence, reasons)| ServerRole {
+                host: host.to_string(),
+                port: *port,
+                proto: protocols.protocol(*proto),
+                confidence,
+                reasons,
+            })
+            .collect()
     }
 
-    pub fn servers(&self) -> Vec<(String, u16, String)> {
+    // hosts answering protocols without ports, e.g. icmp echo or ipsec esp.
+    pub fn portless(&self, protocols: &ProtocolTable) -> Vec<ServerRole> {
         let mut hosts = self
             .hosts
             .iter()
-            .filter(|((_, port, _), cnt)| **cnt > 3 && *port != 0)
-            .map(|(k, _)| k.clone())
+            .filter(|((_, port, proto), obs)| {
+                *port == 0
+                    && !protocols::has_ports(*proto)
+                    && !obs.clients.is_empty()
+                    && obs.server_bytes > 0
+            })
             .collect::<Vec<_>>();
-        hosts.sort();
-        hosts.dedup();
+        hosts.sort_by_key(|((host, _, proto), _)| (*host, *proto));
         hosts
+            .into_iter()
+            .map(|((host, port, proto), obs)| ServerRole {
+                host: host.to_string(),
+                port: *port,
+                proto: protocols.protocol(*proto),
+                confidence: 1.0,
+                reasons: vec![
+                    format!("{} distinct clients", obs.clients.len()),
+                    format!("{} sessions", obs.sessions),
+                    format!("{} bytes answered", obs.server_bytes),
+                ],
+            })
+            .collect()
+    }
+
+    // add the counters of another run, e.g. one loaded from a state file.
+    pub fn merge(&mut self, other: Hosts) {
+        for (key, obs) in other.hosts {
+            self.hosts.entry(key).or_default().merge(obs);
+        }
     }
 
     pub fn hosts(&self) -> Vec<String> {
-        let mut hosts = self.hosts.keys().map(|k| k.0.clone()).collect::<Vec<_>>();
-        hosts.sort();
+        let mut hosts = self.hosts.keys().map(|k| k.0).collect::<Vec<_>>();
+        hosts.sort_unstable();
         hosts.dedup();
-        hosts
+        hosts.iter().map(ToString::to_string).collect()
+    }
+}
+
+impl Serialize for Hosts {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut records = self.hosts.iter().collect::<Vec<_>>();
+        records.sort_by_key(|r| r.0);
+        serializer.collect_seq(
+            records
+                .into_iter()
+                .map(|((host, port, proto), obs)| RecordRef {
+                    host: *host,
+                    port: *port,
+                    proto: *proto,
+                    obs,
+                }),
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for Hosts {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let records = Vec::<Record>::deserialize(deserializer)?;
+        Ok(Self {
+            hosts: records
+                .into_iter()
+                .map(|r| ((r.host, r.port, r.proto), r.obs))
+                .collect(),
+        })
+    }
+}
+
+impl Observation {
+    fn merge(&mut self, other: Observation) {
+        self.sessions += other.sessions;
+        self.clients.extend(other.clients);
+        self.client_bytes = self.client_bytes.saturating_add(other.client_bytes);
+        self.server_bytes = self.server_bytes.saturating_add(other.server_bytes);
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn server_score(&self, port: u16, conf: &RoleConfig) -> (f64, Vec<String>) {
+        let mut reasons = Vec::new();
+
+        let clients = self.clients.len();
+        let mut score = WEIGHT_CLIENTS * clients.min(FULL_CLIENTS) as f64 / FULL_CLIENTS as f64;
+        reasons.push(format!("{clients} distinct clients"));
+
+        score += WEIGHT_SESSIONS * self.sessions.min(FULL_SESSIONS) as f64 / FULL_SESSIONS as f64;
+        reasons.push(format!("{} sessions", self.sessions));
+
+        // servers usually answer with more bytes than they are asked with.
+        if self.client_bytes > 0 || self.server_bytes > 0 {
+            let ratio = self.server_bytes as f64 / self.client_bytes.max(1) as f64;
+            score += WEIGHT_BYTES_RATIO * ratio.min(1.0);
+            reasons.push(format!("sent/received {ratio:.2}"));
+        }
+
+        if conf.ephemeral_ports.contains(&port) {
+            reasons.push("ephemeral port".to_string());
+        } else {
+            score += WEIGHT_FIXED_PORT;
+            reasons.push("fixed port".to_string());
+        }
+        (score, reasons)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Hosts, RoleConfig};
+    use crate::protocols::ProtocolTable;
+    use std::net::IpAddr;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn server_with_many_clients() {
+        let mut hosts = Hosts::new();
+        for i in 0..6 {
+            let client = format!("10.0.0.{i}");
+            hosts.insert_session(ip("10.0.1.1"), 443, 6, ip(&client), 500, 5000);
+        }
+        let servers = hosts.servers(&RoleConfig::default(), &ProtocolTable::builtin());
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].port, 443);
+        assert!(servers[0].confidence > 0.9);
+        assert!(servers[0]
+            .reasons
+            .contains(&"6 distinct clients".to_string()));
+    }
+
+    #[test]
+    fn busy_client_on_ephemeral_port() {
+        let mut hosts = Hosts::new();
+        for _ in 0..20 {
+            hosts.insert_session(ip("10.0.1.2"), 51234, 17, ip("10.0.0.9"), 5000, 100);
+        }
+        assert!(hosts
+            .servers(&RoleConfig::default(), &ProtocolTable::builtin())
+            .is_empty());
+
+        let conf = RoleConfig {
+            threshold: 0.2,
+            ..RoleConfig::default()
+        };
+        assert_eq!(hosts.servers(&conf, &ProtocolTable::builtin()).len(), 1);
+    }
+
+    #[test]
+    fn portless_responders() {
+        let mut hosts = Hosts::new();
+        hosts.insert(ip("10.0.0.9"), 0, 1);
+        hosts.insert_session(ip("10.0.1.3"), 0, 1, ip("10.0.0.9"), 64, 64);
+        hosts.insert_session(ip("10.0.1.4"), 0, 1, ip("10.0.0.9"), 64, 0);
+        let portless = hosts.portless(&ProtocolTable::builtin());
+        assert_eq!(portless.len(), 1);
+        assert_eq!(portless[0].host, "10.0.1.3");
+        assert_eq!(portless[0].proto.name, "icmp");
+        assert!(hosts
+            .servers(&RoleConfig::default(), &ProtocolTable::builtin())
+            .is_empty());
+    }
+
+    #[test]
+    fn merge_saved_state() {
+        let mut day1 = Hosts::new();
+        for i in 0..3 {
+            let client = format!("10.0.0.{i}");
+            day1.insert_session(ip("10.0.1.1"), 443, 6, ip(&client), 500, 5000);
+        }
+        let json = serde_json::to_string(&day1).unwrap();
+
+        let mut day2 = Hosts::new();
+        for i in 2..5 {
+            let client = format!("10.0.0.{i}");
+            day2.insert_session(ip("10.0.1.1"), 443, 6, ip(&client), 500, 5000);
+        }
+        day2.merge(serde_json::from_str(&json).unwrap());
+
+        let obs = &day2.hosts[&(ip("10.0.1.1"), 443, 6)];
+        assert_eq!(obs.sessions, 6);
+        assert_eq!(obs.clients.len(), 5);
+        assert_eq!(obs.server_bytes, 30000);
+        assert_eq!(
+            day2.servers(&RoleConfig::default(), &ProtocolTable::builtin())
+                .len(),
+            1
+        );
     }
 }
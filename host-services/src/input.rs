@@ -0,0 +1,175 @@
+use anyhow::{anyhow, Result};
+use flate2::bufread::MultiGzDecoder;
+use regex::Regex;
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    path::Path,
+};
+
+// path that reads standard input.
+pub const STDIN: &str = "-";
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// open a file, or standard input for "-".
+// gzip compressed files, including concatenated gzip members, are decompressed.
+pub fn open(path: &str) -> Result<Box<dyn BufRead + Send>> {
+    if path == STDIN {
+        return Ok(Box::new(BufReader::new(std::io::stdin())));
+    }
+    let mut rdr = BufReader::new(File::open(path)?);
+    if !rdr.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        return Ok(Box::new(rdr));
+    }
+    Ok(Box::new(BufReader::new(MultiGzDecoder::new(rdr))))
+}
+
+// read about `size` bytes, extended to the end of the line. `None` at the end of input.
+// records must not span lines, which holds for conn logs.
+pub fn read_chunk<R: BufRead + ?Sized>(
+    rdr: &mut R,
+    size: usize,
+) -> std::io::Result<Option<Vec<u8>>> {
+    let mut chunk = Vec::with_capacity(size + 1024);
+    rdr.take(size as u64).read_to_end(&mut chunk)?;
+    if chunk.is_empty() {
+        return Ok(None);
+    }
+    if chunk.last() != Some(&b'\n') {
+        rdr.read_until(b'\n', &mut chunk)?;
+    }
+    Ok(Some(chunk))
+}
+
+// expand file name patterns (`*`, `?`) in the last path component.
+// patterns without wildcards and "-" are kept as they are.
+pub fn expand(patterns: &[String]) -> Result<Vec<String>> {
+    let mut paths = Vec::new();
+    for pattern in patterns {
+        if pattern == STDIN || !pattern.contains(['*', '?']) {
+            paths.push(pattern.clone());
+            continue;
+        }
+        let path = Path::new(pattern);
+        let dir = match path.parent() {
+            Some(d) if !d.as_os_str().is_empty() => d,
+            _ => Path::new("."),
+        };
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| anyhow!("invalid file pattern {pattern}"))?;
+        if dir.to_string_lossy().contains(['*', '?']) {
+            return Err(anyhow!(
+                "wildcards are allowed in file names only: {pattern}"
+            ));
+        }
+        let re = glob_regex(name)?;
+        let mut matched = std::fs::read_dir(dir)?
+            .filter_map(Result::ok)
+            .filter(|e| e.file_type().is_ok_and(|t| t.is_file()))
+            .filter(|e| e.file_name().to_str().is_some_and(|n| re.is_match(n)))
+            .map(|e| {
+                if path.parent().is_some_and(|d| d.as_os_str().is_empty()) {
+                    e.file_name().to_string_lossy().to_string()
+                } else {
+                    e.path().to_string_lossy().to_string()
+                }
+            })
+            .collect::<Vec<_>>();
+        if matched.is_empty() {
+            return Err(anyhow!("no files match {pattern}"));
+        }
+        matched.sort();
+        paths.extend(matched);
+    }
+    Ok(paths)
+}
+
+fn glob_regex(name: &str) -> Result<Regex> {
+    let mut re = String::from("^");
+    for c in name.chars() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    Ok(Regex::new(&re)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expand, glob_regex, read_chunk};
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::{Read, Write};
+
+    #[test]
+    fn glob_patterns() {
+        let re = glob_regex("conn.2023-??-*.log").unwrap();
+        assert!(re.is_match("conn.2023-01-01.log"));
+        assert!(!re.is_match("conn.2023-1-01.log"));
+        assert!(!re.is_match("conn.2023-01-01.log.gz"));
+    }
+
+    #[test]
+    fn chunks_end_at_lines() {
+        let mut rdr = "a\tb\nccc\td\ne\n".as_bytes();
+        assert_eq!(read_chunk(&mut rdr, 2).unwrap(), Some(b"a\tb\n".to_vec()));
+        assert_eq!(read_chunk(&mut rdr, 6).unwrap(), Some(b"ccc\td\n".to_vec()));
+        assert_eq!(read_chunk(&mut rdr, 100).unwrap(), Some(b"e\n".to_vec()));
+        assert_eq!(read_chunk(&mut rdr, 100).unwrap(), None);
+    }
+
+    #[test]
+    fn gzip_input() {
+        let path =
+            std::env::temp_dir().join(format!("host-services-{}.log.gz", std::process::id()));
+        // two gzip members, as written by `cat a.gz b.gz`
+        let mut gz = Vec::new();
+        for part in ["a\n", "b\n"] {
+            let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+            enc.write_all(part.as_bytes()).unwrap();
+            gz.extend(enc.finish().unwrap());
+        }
+        std::fs::write(&path, &gz).unwrap();
+        let mut s = String::new();
+        super::open(path.to_str().unwrap())
+            .unwrap()
+            .read_to_string(&mut s)
+            .unwrap();
+        assert_eq!(s, "a\nb\n");
+
+        // a truncated file is an error, not a shorter input
+        std::fs::write(&path, &gz[..gz.len() - 4]).unwrap();
+        let mut s = String::new();
+        let read = super::open(path.to_str().unwrap())
+            .unwrap()
+            .read_to_string(&mut s);
+        std::fs::remove_file(&path).unwrap();
+        assert!(read.is_err());
+    }
+
+    #[test]
+    fn expand_files() {
+        let dir = std::env::temp_dir().join(format!("host-services-input-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["b.log", "a.log", "c.txt"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let pattern = format!("{}/*.log", dir.display());
+        let paths = expand(&[pattern, "-".to_string()]).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            paths,
+            vec![
+                format!("{}/a.log", dir.display()),
+                format!("{}/b.log", dir.display()),
+                "-".to_string()
+            ]
+        );
+        assert!(expand(&[format!("{}/*.log", dir.display())]).is_err());
+    }
+}
@@ -0,0 +1,556 @@
+use crate::{hosts::ServerRole, services::ServiceRegistry};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+};
+
+// first and last time something was seen in the input.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Seen {
+    first: DateTime<Utc>,
+    last: DateTime<Utc>,
+}
+
+impl Seen {
+    fn insert(seen: &mut Option<Seen>, t: DateTime<Utc>) {
+        Self::merge(seen, Some(Seen { first: t, last: t }));
+    }
+
+    fn merge(seen: &mut Option<Seen>, other: Option<Seen>) {
+        match (seen.as_mut(), other) {
+            (Some(s), Some(o)) => {
+                s.first = s.first.min(o.first);
+                s.last = s.last.max(o.last);
+            }
+            (None, o) => *seen = o,
+            (_, None) => {}
+        }
+    }
+}
+
+// a service with the name detected by the sensor, if any.
+#[derive(Debug, Default)]
+struct ServiceSighting {
+    seen: Option<Seen>,
+    name: Option<String>,
+}
+
+impl ServiceSighting {
+    fn merge(&mut self, other: ServiceSighting) {
+        Seen::merge(&mut self.seen, other.seen);
+        if self.name.is_none() {
+            self.name = other.name;
+        }
+    }
+}
+
+// first-seen and last-seen timestamps of local hosts and their services.
+// services are keyed by (host, port, protocol number).
+#[derive(Debug, Default)]
+pub struct Sightings {
+    hosts: HashMap<IpAddr, Option<Seen>>,
+    services: HashMap<(IpAddr, u16, u8), ServiceSighting>,
+}
+
+impl Sightings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert_host(&mut self, host: IpAddr, t: DateTime<Utc>) {
+        Seen::insert(self.hosts.entry(host).or_default(), t);
+    }
+
+    pub fn merge(&mut self, other: Sightings) {
+        for (host, seen) in other.hosts {
+            Seen::merge(self.hosts.entry(host).or_default(), seen);
+        }
+        for (key, sighting) in other.services {
+            self.services.entry(key).or_default().merge(sighting);
+        }
+    }
+
+    // `name` is the service name detected by the sensor, if any.
+    pub fn insert_service(&mut self, key: (IpAddr, u16, u8), name: Option<&str>, t: DateTime<Utc>) {
+        let sighting = self.services.entry(key).or_default();
+        Seen::insert(&mut sighting.seen, t);
+        if sighting.name.is_none() {
+            sighting.name = name.map(ToString::to_string);
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct HostRecord {
+    pub host: String,
+    pub first_seen: Option<DateTime<Utc>>,
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ServiceRecord {
+    pub host: String,
+    // 0 for port-less protocols.
+    pub port: u16,
+    pub proto: String,
+    pub service: Option<String>,
+    pub first_seen: Option<DateTime<Utc>>,
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+impl ServiceRecord {
+    fn key(&self) -> (&str, u16, &str) {
+        (&self.host, self.port, &self.proto)
+    }
+}
+
+// hosts and servers found in a run. saved as a json baseline for later runs.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Inventory {
+    pub generated: Option<DateTime<Utc>>,
+    pub hosts: Vec<HostRecord>,
+    pub services: Vec<ServiceRecord>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PortChange {
+    pub host: String,
+    pub proto: String,
+    pub service: Option<String>,
+    pub from: u16,
+    pub to: u16,
+}
+
+// differences of the current inventory from a baseline.
+#[derive(Debug, Default)]
+pub struct Drift {
+    pub new_hosts: Vec<HostRecord>,
+    pub new_services: Vec<ServiceRecord>,
+    pub gone_services: Vec<ServiceRecord>,
+    pub port_changes: Vec<PortChange>,
+}
+
+impl Inventory {
+    pub fn new(
+        hosts: &[String],
+        servers: &[ServerRole],
+        sightings: &Sightings,
+        registry: &ServiceRegistry,
+    ) -> Self {
+        let hosts = hosts
+            .iter()
+            .map(|host| {
+                let seen = host
+                    .parse::<IpAddr>()
+                    .ok()
+                    .and_then(|h| sightings.hosts.get(&h).copied().flatten());
+                HostRecord {
+                    host: host.clone(),
+                    first_seen: seen.map(|s| s.first),
+                    last_seen: seen.map(|s| s.last),
+                }
+            })
+            .collect();
+        let mut services = servers
+            .iter()
+            .map(|s| {
+                let sighting = s
+                    .host
+                    .parse::<IpAddr>()
+                    .ok()
+                    .and_then(|h| sightings.services.get(&(h, s.port, s.proto.number)));
+                let seen = sighting.and_then(|s| s.seen);
+                let detected = sighting.and_then(|s| s.name.as_deref());
+                ServiceRecord {
+                    host: s.host.clone(),
+                    port: s.port,
+                    proto: s.proto.name.clone(),
+                    service: registry.label(detected, s.port, &s.proto.name),
+                    first_seen: seen.map(|s| s.first),
+                    last_seen: seen.map(|s| s.last),
+                }
+            })
+            .collect::<Vec<_>>();
+        services.sort_by(|a, b| a.key().cmp(&b.key()));
+        Self {
+            generated: Some(Utc::now()),
+            hosts,
+            services,
+        }
+    }
+
+    pub fn from_path(path: &str) -> Result<Self> {
+        let s = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&s)?)
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    // keep the earlier first-seen time of hosts and services already in the baseline.
+    pub fn carry_over(&mut self, baseline: &Inventory) {
+        let hosts = baseline
+            .hosts
+            .iter()
+            .map(|h| (h.host.as_str(), h))
+            .collect::<HashMap<_, _>>();
+        for h in &mut self.hosts {
+            if let Some(old) = hosts.get(h.host.as_str()) {
+                h.first_seen = earliest(h.first_seen, old.first_seen);
+            }
+        }
+        let services = baseline
+            .services
+            .iter()
+            .map(|s| (s.key(), s))
+            .collect::<HashMap<_, _>>();
+        for s in &mut self.services {
+            if let Some(old) = services.get(&s.key()) {
+                s.first_seen = earliest(s.first_seen, old.first_seen);
+            }
+        }
+    }
+
+    pub fn drift(&self, baseline: &Inventory) -> Drift {
+        let old_hosts = baseline
+            .hosts
+            .iter()
+            .map(|h| h.host.as_str())
+            .collect::<std::collections::HashSet<_>>();
+        let new_hosts = self
+            .hosts
+            .iter()
+            .filter(|h| !old_hosts.contains(h.host.as_str()))
+            .cloned()
+            .collect();
+
+        let old = baseline
+            .services
+            .iter()
+            .map(|s| (s.key(), s))
+            .collect::<BTreeMap<_, _>>();
+        let cur = self
+            .services
+            .iter()
+            .map(|s| (s.key(), s))
+            .collect::<BTreeMap<_, _>>();
+        let mut added = cur
+            .iter()
+            .filter(|(k, _)| !old.contains_key(*k))
+            .map(|(_, s)| (*s).clone())
+            .collect::<Vec<_>>();
+        let mut gone = old
+            .iter()
+            .filter(|(k, _)| !cur.contains_key(*k))
+            .map(|(_, s)| (*s).clone())
+            .collect::<Vec<_>>();
+        let port_changes = pair_port_changes(&mut added, &mut gone);
+
+        Drift {
+            new_hosts,
+            new_services: added,
+            gone_services: gone,
+            port_changes,
+        }
+    }
+}
+
+fn earliest(a: Option<DateTime<Utc>>, b: Option<DateTime<Utc>>) -> Option<DateTime<Utc>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+// a service that left one port and appeared on another of the same host is a port change.
+// gone and new services are paired when they are the only ones with the same host,
+// protocol and service name. names are the ones detected by the sensor where there is
+// one, so a service keeps its name on a port the registry assigns to another service.
+// services without a name are paired only when they are the only gone and new services
+// of the host and protocol.
+fn pair_port_changes(
+    added: &mut Vec<ServiceRecord>,
+    gone: &mut Vec<ServiceRecord>,
+) -> Vec<PortChange> {
+    let mut changes = pair_by(added, gone, |s| {
+        s.service
+            .clone()
+            .map(|name| (s.host.clone(), s.proto.clone(), name))
+    });
+    changes.extend(pair_by(added, gone, |s| {
+        Some((s.host.clone(), s.proto.clone()))
+    }));
+    changes.sort_by(|a, b| (&a.host, a.from).cmp(&(&b.host, b.from)));
+    changes
+}
+
+// services without a key are never paired.
+fn pair_by<K, F>(
+    added: &mut Vec<ServiceRecord>,
+    gone: &mut Vec<ServiceRecord>,
+    key: F,
+) -> Vec<PortChange>
+where
+    K: Eq + std::hash::Hash,
+    F: Fn(&ServiceRecord) -> Option<K>,
+{
+    let mut groups: HashMap<K, (Vec<usize>, Vec<usize>)> = HashMap::new();
+    for (i, s) in added.iter().enumerate() {
+        if let Some(k) = key(s) {
+            groups.entry(k).or_default().0.push(i);
+        }
+    }
+    for (j, s) in gone.iter().enumerate() {
+        if let Some(k) = key(s) {
+            groups.entry(k).or_default().1.push(j);
+        }
+    }
+
+    let mut pairs = groups
+        .into_values()
+        .filter(|(a, g)| a.len() == 1 && g.len() == 1)
+        .map(|(a, g)| (a[0], g[0]))
+        .filter(|(i, j)| added[*i].service == gone[*j].service)
+        .collect::<Vec<_>>();
+    let changes = pairs
+        .iter()
+        .map(|(i, j)| PortChange {
+            host: added[*i].host.clone(),
+            proto: added[*i].proto.clone(),
+            service: gone[*j].service.clone(),
+            from: gone[*j].port,
+            to: added[*i].port,
+        })
+        .collect();
+
+    // remove from the back so the remaining indices stay valid.
+    pairs.sort_unstable_by_key(|p| std::cmp::Reverse(p.0));
+    for (i, _) in &pairs {
+        added.remove(*i);
+    }
+    pairs.sort_unstable_by_key(|p| std::cmp::Reverse(p.1));
+    for (_, j) in &pairs {
+        gone.remove(*j);
+    }
+    changes
+}
+
+impl Drift {
+    pub fn print(&self) {
+        let service = |s: &ServiceRecord| s.service.clone().unwrap_or("-".to_string());
+        let time = |t: Option<DateTime<Utc>>| t.map_or("-".to_string(), |t| t.to_rfc3339());
+
+        println!("\nnew hosts {} entries", self.new_hosts.len());
+        println!("host\tfirst_seen");
+        for h in &self.new_hosts {
+            println!("{}\t{}", h.host, time(h.first_seen));
+        }
+        println!("\nnew services {} entries", self.new_services.len());
+        println!("host\tport\tproto\tservice\tfirst_seen");
+        for s in &self.new_services {
+            println!(
+                "{}\t{}\t{}\t{}\t{}",
+                s.host,
+                s.port,
+                s.proto,
+                service(s),
+                time(s.first_seen)
+            );
+        }
+        println!(
+            "\ndisappeared services {} entries",
+            self.gone_services.len()
+        );
+        println!("host\tport\tproto\tservice\tlast_seen");
+        for s in &self.gone_services {
+            println!(
+                "{}\t{}\t{}\t{}\t{}",
+                s.host,
+                s.port,
+                s.proto,
+                service(s),
+                time(s.last_seen)
+            );
+        }
+        println!("\nport changes {} entries", self.port_changes.len());
+        println!("host\tproto\tservice\tfrom\tto");
+        for c in &self.port_changes {
+            println!(
+                "{}\t{}\t{}\t{}\t{}",
+                c.host,
+                c.proto,
+                c.service.as_deref().unwrap_or("-"),
+                c.from,
+                c.to
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HostRecord, Inventory, ServiceRecord, Sightings};
+    use crate::{hosts::ServerRole, protocols::Protocol, services::ServiceRegistry};
+    use chrono::{DateTime, Utc};
+
+    fn at(secs: i64) -> Option<DateTime<Utc>> {
+        DateTime::from_timestamp(1_700_000_000 + secs, 0)
+    }
+
+    fn host(host: &str, t: i64) -> HostRecord {
+        HostRecord {
+            host: host.to_string(),
+            first_seen: at(t),
+            last_seen: at(t),
+        }
+    }
+
+    fn service(host: &str, port: u16, name: Option<&str>, t: i64) -> ServiceRecord {
+        ServiceRecord {
+            host: host.to_string(),
+            port,
+            proto: "tcp".to_string(),
+            service: name.map(ToString::to_string),
+            first_seen: at(t),
+            last_seen: at(t),
+        }
+    }
+
+    fn baseline() -> Inventory {
+        Inventory {
+            generated: at(0),
+            hosts: vec![host("10.0.0.1", 0), host("10.0.0.2", 0)],
+            services: vec![
+                service("10.0.0.1", 22, Some("SSH"), 0),
+                service("10.0.0.1", 80, Some("HTTP"), 0),
+                service("10.0.0.2", 8080, None, 0),
+                service("10.0.0.2", 5432, Some("POSTGRESQL"), 0),
+            ],
+        }
+    }
+
+    #[test]
+    fn drift_from_baseline() {
+        let mut current = Inventory {
+            generated: at(100),
+            hosts: vec![
+                host("10.0.0.1", 100),
+                host("10.0.0.2", 100),
+                host("10.0.0.3", 100),
+            ],
+            services: vec![
+                service("10.0.0.1", 2222, None, 100),
+                service("10.0.0.1", 80, Some("HTTP"), 100),
+                service("10.0.0.2", 8081, None, 100),
+                service("10.0.0.2", 5433, Some("POSTGRESQL"), 100),
+                service("10.0.0.3", 3389, Some("MS-WBT-SERVER"), 100),
+            ],
+        };
+        let ports = |services: &[super::ServiceRecord]| {
+            services
+                .iter()
+                .map(|s| (s.host.clone(), s.port))
+                .collect::<Vec<_>>()
+        };
+        let changes = |drift: &super::Drift| {
+            drift
+                .port_changes
+                .iter()
+                .map(|c| (c.host.clone(), c.from, c.to))
+                .collect::<Vec<_>>()
+        };
+        let drift = current.drift(&baseline());
+        assert_eq!(drift.new_hosts, vec![host("10.0.0.3", 100)]);
+        // a new service without a name is not taken for the ssh service that went away
+        assert_eq!(
+            ports(&drift.new_services),
+            [
+                ("10.0.0.1".to_string(), 2222),
+                ("10.0.0.3".to_string(), 3389)
+            ]
+        );
+        assert_eq!(ports(&drift.gone_services), [("10.0.0.1".to_string(), 22)]);
+        assert_eq!(
+            changes(&drift),
+            [
+                ("10.0.0.2".to_string(), 5432, 5433),
+                ("10.0.0.2".to_string(), 8080, 8081)
+            ]
+        );
+
+        // another new service without a name makes the pairing ambiguous
+        current.services.push(service("10.0.0.2", 9000, None, 100));
+        let drift = current.drift(&baseline());
+        assert_eq!(changes(&drift), [("10.0.0.2".to_string(), 5432, 5433)]);
+        assert_eq!(drift.new_services.len(), 4);
+        assert_eq!(drift.gone_services.len(), 2);
+    }
+
+    #[test]
+    fn detected_service_names() {
+        let mut registry = ServiceRegistry::new();
+        registry
+            .read_etc_services("ssh 22/tcp\nEtherNet/IP-1 2222/tcp\nhttp 80/tcp www\n".as_bytes())
+            .unwrap();
+        let tcp = Protocol {
+            number: 6,
+            name: "tcp".to_string(),
+        };
+        let server = |port: u16| ServerRole {
+            host: "10.0.0.1".to_string(),
+            port,
+            proto: tcp.clone(),
+            confidence: 1.0,
+            reasons: Vec::new(),
+        };
+        let servers = [server(2222), server(80), server(8080)];
+        let mut sightings = Sightings::new();
+        let t = at(100).unwrap();
+        let host = "10.0.0.1".parse().unwrap();
+        sightings.insert_service((host, 2222, 6), Some("ssh"), t);
+        sightings.insert_service((host, 80, 6), Some("www"), t);
+        sightings.insert_service((host, 8080, 6), None, t);
+        let current = Inventory::new(&[], &servers, &sightings, &registry);
+        let names = current
+            .services
+            .iter()
+            .map(|s| s.service.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(names, [Some("HTTP"), Some("SSH"), None]);
+
+        // ssh moved to a port the registry names EtherNet/IP-1
+        let drift = current.drift(&baseline());
+        let changes = drift
+            .port_changes
+            .iter()
+            .map(|c| (c.service.as_deref(), c.from, c.to))
+            .collect::<Vec<_>>();
+        assert_eq!(changes, [(Some("SSH"), 22, 2222)]);
+    }
+
+    #[test]
+    fn baseline_round_trip() {
+        let baseline = baseline();
+        let json = serde_json::to_string(&baseline).unwrap();
+        let loaded: Inventory = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.services, baseline.services);
+
+        let mut current = Inventory {
+            generated: at(100),
+            hosts: vec![host("10.0.0.1", 100)],
+            services: vec![service("10.0.0.1", 22, Some("SSH"), 100)],
+        };
+        current.carry_over(&loaded);
+        assert_eq!(current.hosts[0].first_seen, at(0));
+        assert_eq!(current.services[0].first_seen, at(0));
+        assert_eq!(current.services[0].last_seen, at(100));
+        let drift = current.drift(&current.clone());
+        assert!(drift.new_hosts.is_empty() && drift.new_services.is_empty());
+        assert!(drift.gone_services.is_empty() && drift.port_changes.is_empty());
+    }
+}
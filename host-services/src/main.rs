@@ -1,99 +1,299 @@
+mod activity;
+mod collect;
+mod firewall;
+mod graph;
 mod hosts;
+mod input;
+mod inventory;
 mod net;
+mod netdb;
+mod protocols;
+mod scan;
+mod schema;
 mod services;
+mod sessions;
+mod state;
+mod traffic;
+mod zone;
 
 use crate::net::IpMapper;
-use anyhow::Result;
-use hosts::Hosts;
-use std::collections::HashMap;
+use anyhow::{anyhow, Result};
+use collect::{Collector, Context};
+use firewall::{Compliance, Ruleset};
+use graph::GraphFormat;
+use hosts::{Hosts, RoleConfig};
+use inventory::Inventory;
+use scan::{ScanConfig, ScanDetector};
+use schema::{Format, Schema};
+use state::{FileId, State};
+use std::net::IpAddr;
 use structopt::StructOpt;
+use tracing::warn;
+use zone::ZonePolicy;
 
 #[derive(Debug, structopt::StructOpt)]
 struct Config {
-    #[structopt(short, long, help = "filename to read")]
-    filename: String,
+    #[structopt(
+        short,
+        long,
+        required = true,
+        help = "files to read. wildcards in file names are expanded, \"-\" reads stdin. \
+                gzip compressed files are decompressed"
+    )]
+    filename: Vec<String>,
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "worker threads reading each file. 0 uses every cpu"
+    )]
+    threads: usize,
+    #[structopt(
+        long,
+        help = "state file to merge host counters into across runs. only hosts and servers \
+                include earlier runs. files already merged, by canonical path, size and \
+                modification time, are skipped"
+    )]
+    state: Option<String>,
+    #[structopt(
+        long,
+        default_value = "inventory",
+        possible_values = &["inventory", "scan", "firewall"],
+        help = "report to produce"
+    )]
+    mode: Mode,
+    #[structopt(
+        long,
+        default_value = "giganto",
+        possible_values = &["giganto", "zeek", "csv"],
+        help = "input file format"
+    )]
+    format: Format,
+    #[structopt(
+        long,
+        help = "IANA service-names-port-numbers.csv to label unregistered ports"
+    )]
+    iana: Option<String>,
+    #[structopt(short, long, help = "zone policy file (toml)")]
+    policy: Option<String>,
+    #[structopt(
+        long,
+        default_value = "0.6",
+        help = "minimum confidence (0.0 ~ 1.0) to report a host/port as a server"
+    )]
+    server_threshold: f64,
+    #[structopt(
+        long,
+        default_value = "32768-65535",
+        help = "ephemeral port range used by clients"
+    )]
+    ephemeral_ports: String,
+    #[structopt(long, default_value = "10", help = "number of top talkers to report")]
+    top: usize,
+    #[structopt(long, help = "write traffic statistics to this json file")]
+    traffic_json: Option<String>,
+    #[structopt(
+        long,
+        default_value = "0",
+        allow_hyphen_values = true,
+        help = "utc offset in hours for activity profiles"
+    )]
+    utc_offset: i32,
+    #[structopt(long, help = "print hourly and weekday activity profiles")]
+    activity: bool,
+    #[structopt(long, help = "report drift from this inventory baseline (json)")]
+    baseline: Option<String>,
+    #[structopt(
+        long,
+        help = "save hosts and servers to this inventory baseline (json)"
+    )]
+    save_baseline: Option<String>,
+    #[structopt(long, help = "write the host-to-host communication graph to this file")]
+    graph: Option<String>,
+    #[structopt(
+        long,
+        default_value = "dot",
+        possible_values = &["dot", "gexf", "json"],
+        help = "graph file format"
+    )]
+    graph_format: GraphFormat,
+    #[structopt(long, default_value = "300", help = "scan detection window in seconds")]
+    scan_window: i64,
+    #[structopt(
+        long,
+        default_value = "20",
+        help = "distinct ports on one host to report a vertical scan"
+    )]
+    scan_ports: usize,
+    #[structopt(
+        long,
+        default_value = "20",
+        help = "distinct hosts on one port to report a horizontal sweep"
+    )]
+    scan_hosts: usize,
+    #[structopt(
+        long,
+        help = "ufw rules to check in firewall mode: `ufw status numbered` output or user.rules"
+    )]
+    ufw: Option<String>,
+    #[structopt(
+        long,
+        help = "host the ufw rules belong to. every local host if not given"
+    )]
+    ufw_host: Option<IpAddr>,
+    #[structopt(
+        long,
+        default_value = "/etc/ufw/applications.d",
+        help = "ufw application profiles giving the ports of rules such as \"OpenSSH\". \
+                servers that a profile not found there may decide are listed apart"
+    )]
+    ufw_apps: String,
+    #[structopt(
+        long,
+        default_value = "3600",
+        help = "sessions at least this many seconds long are listed as long-lived. only the \
+                --top longest are kept"
+    )]
+    long_session: i64,
     // #[structopt(short, long, help = "option")]
     // opt: Option<String>,
 }
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Mode {
+    // hosts, servers, zones, traffic and activity
+    Inventory,
+    // horizontal and vertical scans
+    Scan,
+    // servers and ufw allow rules compared
+    Firewall,
+}
+
+impl std::str::FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "inventory" => Ok(Mode::Inventory),
+            "scan" => Ok(Mode::Scan),
+            "firewall" => Ok(Mode::Firewall),
+            _ => Err(format!("unknown mode {s}")),
+        }
+    }
+}
+
 const LOCAL_NETWORKS: [&str; 3] = ["10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16"];
 
 fn main() {
     tracing_subscriber::fmt::init();
 
     let conf = Config::from_args();
-    let Ok(services) = services::build("/etc/services") else {
+    let Ok(mut services) = services::build("/etc/services") else {
         eprintln!("Error: fail to parse /etc/services");
         std::process::exit(1);
     };
+    if let Some(path) = &conf.iana {
+        if let Err(e) = std::fs::File::open(path)
+            .map_err(Into::into)
+            .and_then(|f| services.read_iana_csv(f))
+        {
+            eprintln!("Error: fail to parse {path}. {e}");
+            std::process::exit(1);
+        }
+    }
 
-    let local_net = IpMapper::build(
-        &LOCAL_NETWORKS
-            .iter()
-            .map(ToString::to_string)
-            .collect::<Vec<_>>(),
-    )
-    .unwrap_or(IpMapper::new());
+    let policy = match &conf.policy {
+        Some(path) => match ZonePolicy::from_path(path) {
+            Ok(policy) => policy,
+            Err(e) => {
+                eprintln!("Error: fail to parse {path}. {e}");
+                std::process::exit(1);
+            }
+        },
+        None => ZonePolicy::with_local_networks(&LOCAL_NETWORKS),
+    };
+    let local_net = policy.local_networks().unwrap_or(IpMapper::new());
+    let zones = policy.zones().unwrap_or(IpMapper::new());
+
+    let Some(ephemeral_ports) = parse_port_range(&conf.ephemeral_ports) else {
+        eprintln!("Error: invalid port range {}", conf.ephemeral_ports);
+        std::process::exit(1);
+    };
+    let role = RoleConfig {
+        threshold: conf.server_threshold,
+        ephemeral_ports,
+    };
+
+    let protocols = protocols::build("/etc/protocols");
+
+    let Some(long_session) = chrono::TimeDelta::try_seconds(conf.long_session)
+        .filter(|d| *d > chrono::TimeDelta::zero())
+    else {
+        eprintln!("Error: invalid long session length {}", conf.long_session);
+        std::process::exit(1);
+    };
 
     // println!("services {:#?}", services);
     println!("services {} entries", services.len());
+    println!("protocols {} entries", protocols.len());
 
-    if let Err(e) = read_csv_file(&conf.filename, &services, &local_net) {
+    let ctx = Context {
+        services,
+        protocols,
+        local_net,
+        zones,
+        role,
+        long_session,
+    };
+    let ret = match conf.mode {
+        Mode::Inventory => read_csv_file(&conf, &ctx),
+        Mode::Scan => scan_report(&conf, &ctx),
+        Mode::Firewall => firewall_report(&conf, &ctx),
+    };
+    if let Err(e) = ret {
         eprintln!("Error: {e}");
     }
 }
 
-const PROTO_TCP: &str = "tcp";
-const PROTO_UDP: &str = "udp";
-
-// 다음과 같은 필드로 구성된 TAB separated CSV 파일을 읽어서 destination ip address, destination port 종류와 등장 횟수를 계산하는 함수를 작성하시오.
-// fields: timestamp, source name, source ip address, source port, destination ip address, destination port, protocol, session end time, service name, sent bytes, received bytes, send packets, received packets
-fn read_csv_file(
-    filename: &str,
-    services: &HashMap<String, String>,
-    local_net: &IpMapper,
-) -> Result<()> {
-    let mut rdr = csv::ReaderBuilder::new()
-        .delimiter(b'\t')
-        .flexible(true)
-        .from_path(filename)?;
-    let mut hosts = Hosts::new();
-    for rec in rdr.records().flatten() {
-        let Some((src, src_is_local)) = rec.get(2).map(|v| (v.to_string(), local_net.contains(v)))
-        else {
-            continue;
-        };
-        let Some((dest, dest_is_local)) =
-            rec.get(4).map(|v| (v.to_string(), local_net.contains(v)))
-        else {
-            continue;
-        };
-        if !(src_is_local || dest_is_local) {
-            continue;
-        }
-        let Some(dport) = rec.get(5).map(|p| p.parse::<u16>().unwrap_or_default()) else {
-            continue;
+// 입력 파일을 읽어서 destination ip address, destination port 종류와 등장 횟수를 계산한다.
+// 입력 파일의 컬럼 구성은 format 에 따른 Schema 로 결정된다.
+fn read_csv_file(conf: &Config, ctx: &Context) -> Result<()> {
+    let mut state = match &conf.state {
+        Some(path) => State::load(path)?,
+        None => State::default(),
+    };
+    let offset = chrono::FixedOffset::east_opt(conf.utc_offset * 3600)
+        .ok_or_else(|| anyhow!("invalid utc offset {}", conf.utc_offset))?;
+    let threads = match conf.threads {
+        0 => std::thread::available_parallelism().map_or(1, usize::from),
+        n => n,
+    };
+    let mut collector = Collector::new(offset, conf.graph.is_some(), conf.top);
+    for path in input::expand(&conf.filename)? {
+        let id = match (&conf.state, path.as_str()) {
+            (Some(_), p) if p != input::STDIN => Some(FileId::of(p)?),
+            _ => None,
         };
-
-        let Some(proto) = rec.get(6).and_then(|p| {
-            if p == "6" {
-                Some(PROTO_TCP)
-            } else if p == "17" {
-                Some(PROTO_UDP)
-            } else {
-                None
-            }
-        }) else {
+        if id.as_ref().is_some_and(|id| state.files.contains(id)) {
+            warn!("{path} is already merged into the state file");
             continue;
-        };
-
-        if src_is_local {
-            hosts.insert(src.as_str(), 0, proto);
         }
+        collector.read(ctx, conf.format, &path, threads)?;
+        state.files.extend(id);
+    }
+    let Collector {
+        mut hosts,
+        matrix,
+        traffic,
+        activity,
+        graph,
+        sightings,
+        sessions,
+    } = collector;
 
-        if dest_is_local {
-            hosts.insert(dest.as_str(), dport, proto);
-        }
+    if let Some(path) = &conf.state {
+        state.hosts.merge(hosts);
+        state.save(path)?;
+        hosts = state.hosts;
     }
 
     println!("hosts {} entries", hosts.hosts().len());
@@ -101,14 +301,263 @@ fn read_csv_file(
         println!("{host}");
     }
 
-    println!("\nservers {} entries", hosts.servers().len());
-    for (server, port, proto) in hosts.servers() {
-        let service = format!("{port}/{proto}");
+    let servers = hosts.servers(&ctx.role, &ctx.protocols);
+    println!("\nservers {} entries", servers.len());
+    for server in &servers {
+        let service = ctx
+            .services
+            .get(server.port, &server.proto.name)
+            .map_or_else(
+                || format!("{}/{}", server.port, server.proto),
+                |s| s.name.to_uppercase(),
+            );
+        println!(
+            "{}\t{}\t{}\t{}\t{:.2}\t{}",
+            server.host,
+            server.port,
+            server.proto,
+            service,
+            server.confidence,
+            server.reasons.join(", ")
+        );
+    }
+
+    let portless = hosts.portless(&ctx.protocols);
+    println!("\nport-less services {} entries", portless.len());
+    for server in &portless {
+        println!(
+            "{}\t{}\t{}",
+            server.host,
+            server.proto,
+            server.reasons.join(", ")
+        );
+    }
+
+    let quality = sessions.quality(&servers);
+    println!("\nsession quality {} entries", quality.len());
+    println!("host\tport\tproto\tsessions\tmin\tmedian\tp95\tmax\tzero-byte\tone-sided");
+    for q in &quality {
+        let durations = q.durations.map_or_else(
+            || "-\t-\t-\t-".to_string(),
+            |d| d.map(sessions::seconds).join("\t"),
+        );
         println!(
-            "{server}\t{port}\t{proto}\t{}",
-            services.get(&service).unwrap_or(&service)
+            "{}\t{}\t{}\t{}\t{}\t{:.1}%\t{:.1}%",
+            q.server.host,
+            q.server.port,
+            q.server.proto,
+            q.sessions,
+            durations,
+            q.zero_byte * 100.0,
+            q.one_sided * 100.0
         );
     }
 
+    println!(
+        "\nlong sessions {} entries (>= {}s)",
+        sessions.long_count(),
+        conf.long_session
+    );
+    println!("src\tdest\tport\tproto\tstart\tduration");
+    for s in sessions.long_sessions() {
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            s.src,
+            s.dest,
+            s.port,
+            ctx.protocols.protocol(s.proto),
+            s.start.map_or_else(|| "-".to_string(), |t| t.to_rfc3339()),
+            sessions::seconds(s.duration)
+        );
+    }
+
+    matrix.print();
+
+    let report = traffic.report(conf.top, &ctx.services, &ctx.protocols);
+    report.print();
+    if let Some(path) = &conf.traffic_json {
+        std::fs::write(path, serde_json::to_string_pretty(&report)?)?;
+    }
+
+    activity.print(conf.activity, &ctx.protocols);
+
+    if let (Some(path), Some(graph)) = (&conf.graph, graph) {
+        let mut w = std::io::BufWriter::new(std::fs::File::create(path)?);
+        graph.write(conf.graph_format, &mut w, &ctx.protocols)?;
+    }
+
+    if conf.baseline.is_some() || conf.save_baseline.is_some() {
+        let servers = [servers, portless].concat();
+        let mut inventory = Inventory::new(&hosts.hosts(), &servers, &sightings, &ctx.services);
+        if let Some(path) = &conf.baseline {
+            let baseline = Inventory::from_path(path)
+                .map_err(|e| anyhow!("fail to read baseline {path}. {e}"))?;
+            inventory.drift(&baseline).print();
+            inventory.carry_over(&baseline);
+        }
+        if let Some(path) = &conf.save_baseline {
+            inventory.save(path)?;
+        }
+    }
+
     Ok(())
 }
+
+// 로컬 호스트가 관련된 flow 에서 수평/수직 스캔을 찾는다.
+fn scan_report(conf: &Config, ctx: &Context) -> Result<()> {
+    let scan_conf = ScanConfig {
+        window: chrono::TimeDelta::try_seconds(conf.scan_window)
+            .filter(|w| *w > chrono::TimeDelta::zero())
+            .ok_or_else(|| anyhow!("invalid scan window {}", conf.scan_window))?,
+        min_ports: conf.scan_ports,
+        min_hosts: conf.scan_hosts,
+    };
+    let mut detector = ScanDetector::new();
+    for path in input::expand(&conf.filename)? {
+        let (schema, input) = Schema::open(conf.format, &path)?;
+        schema::for_each_record(conf.format.reader(input), |rec| {
+            let Some(flow) = schema.flow(rec) else {
+                return;
+            };
+            if !ctx.local_net.contains(flow.src) && !ctx.local_net.contains(flow.dest) {
+                return;
+            }
+            let Some(t) = flow.time() else {
+                return;
+            };
+            let Some(proto) = ctx.protocols.get(flow.proto) else {
+                return;
+            };
+            if !proto.has_ports() {
+                return;
+            }
+            detector.insert(flow.src, flow.dest, flow.dest_port, &proto, t);
+        })
+        .map_err(|e| anyhow!("fail to read {path}. {e}"))?;
+    }
+
+    let scans = detector.detect(&scan_conf);
+    println!("\nscans {} entries", scans.len());
+    println!("scanner\tkind\tproto\ttargets\tports\tfirst\tlast\tsessions");
+    for scan in scans {
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            scan.scanner,
+            scan.kind,
+            scan.proto,
+            scan.targets.join(","),
+            scan.ports
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(","),
+            scan.first.to_rfc3339(),
+            scan.last.to_rfc3339(),
+            scan.sessions
+        );
+    }
+    Ok(())
+}
+
+// 방화벽 규칙이 허용하지 않는 서버 포트와 트래픽이 없는 허용 규칙을 찾는다.
+fn firewall_report(conf: &Config, ctx: &Context) -> Result<()> {
+    let path = conf
+        .ufw
+        .as_ref()
+        .ok_or_else(|| anyhow!("firewall mode needs --ufw"))?;
+    let mut rules = Ruleset::from_path(path, &ctx.protocols)
+        .map_err(|e| anyhow!("fail to read ufw rules {path}. {e}"))?;
+    rules.resolve_apps(std::path::Path::new(&conf.ufw_apps), &ctx.protocols)?;
+    println!("ufw rules {} entries", rules.rules().len());
+
+    let mut hosts = Hosts::new();
+    let mut compliance = Compliance::new(rules);
+    for path in input::expand(&conf.filename)? {
+        let (schema, input) = Schema::open(conf.format, &path)?;
+        schema::for_each_record(conf.format.reader(input), |rec| {
+            let Some(flow) = schema.flow(rec) else {
+                return;
+            };
+            let (Ok(src), Ok(dest)) = (flow.src.parse::<IpAddr>(), flow.dest.parse::<IpAddr>())
+            else {
+                return;
+            };
+            let protected = match conf.ufw_host {
+                Some(host) => host == dest,
+                None => ctx.local_net.lookup(dest).is_some(),
+            };
+            if !protected {
+                return;
+            }
+            let Some(proto) = ctx.protocols.number(flow.proto) else {
+                return;
+            };
+            if !protocols::has_ports(proto) {
+                return;
+            }
+            hosts.insert_session(
+                dest,
+                flow.dest_port,
+                proto,
+                src,
+                flow.sent_bytes,
+                flow.received_bytes,
+            );
+            compliance.insert(src, dest, flow.dest_port, proto);
+        })
+        .map_err(|e| anyhow!("fail to read {path}. {e}"))?;
+    }
+
+    let servers = hosts.servers(&ctx.role, &ctx.protocols);
+    let unpermitted = compliance.unpermitted(&servers);
+    println!("\nservers without allow rule {} entries", unpermitted.len());
+    println!("host\tport\tproto\tservice\tsessions\tdecided by");
+    for u in &unpermitted {
+        let service = ctx
+            .services
+            .get(u.server.port, &u.server.proto.name)
+            .map_or_else(|| "-".to_string(), |s| s.name.to_uppercase());
+        let decided_by = if u.denied_by.is_empty() {
+            "default policy".to_string()
+        } else {
+            u.denied_by
+                .iter()
+                .map(|n| format!("[{n}]"))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            u.server.host, u.server.port, u.server.proto, service, u.sessions, decided_by
+        );
+    }
+
+    let undecided = compliance.undecided(&servers);
+    println!(
+        "\nservers matching application profiles of unknown ports {} entries",
+        undecided.len()
+    );
+    println!("host\tport\tproto");
+    for server in undecided {
+        println!("{}\t{}\t{}", server.host, server.port, server.proto);
+    }
+
+    let unused = compliance.unused();
+    println!("\nallow rules without traffic {} entries", unused.len());
+    for rule in unused {
+        if rule.unresolved() {
+            println!("{}\t(application profile, ports unknown)", rule.text);
+        } else {
+            println!("{}", rule.text);
+        }
+    }
+    Ok(())
+}
+
+// "32768-65535" => 32768..=65535
+fn parse_port_range(s: &str) -> Option<std::ops::RangeInclusive<u16>> {
+    let (start, end) = s.split_once('-')?;
+    let start = start.trim().parse::<u16>().ok()?;
+    let end = end.trim().parse::<u16>().ok()?;
+    (start <= end).then_some(start..=end)
+}
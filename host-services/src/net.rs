@@ -12,18 +12,27 @@ use tracing::warn;
 #[derive(Clone, Debug)]
 #[allow(clippy::module_name_repetitions)]
 pub struct IpMapper {
+    labels: Vec<String>,
+    // position of each label in `labels`
+    indices: HashMap<String, usize>,
+    v4: Option<IpTree>,
+    v6: Option<IpTree>,
+}
+
+// network groups of one address family, keyed by the shortest netmask.
+#[derive(Clone, Debug)]
+struct IpTree {
     netmask: IpAddr,
     tree: HashMap<IpAddr, IpNode>,
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct IpNode {
-    netmask: Option<IpAddr>,
-    values: Vec<NetGroup>,
+    values: Vec<(usize, NetGroup)>,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
-enum NetGroup {
+pub enum NetGroup {
     IpNet(IpNet),
     IpRange((IpNet, RangeInclusive<IpAddr>)),
 }
@@ -66,13 +75,10 @@ fn rangeinclusive_to_ipnet(v: &RangeInclusive<IpAddr>) -> Result<IpNet> {
     let Ok(mut find_super_net) = IpNet::new(*start, max_prefix_len) else {
         bail!("fail to find super net.");
     };
-    loop {
+    while !find_super_net.contains(end) {
         let Some(s) = find_super_net.supernet() else {
             bail!("fail to find super net.");
         };
-        if s.contains(end) {
-            break;
-        }
         find_super_net = s;
     }
     Ok(find_super_net)
@@ -94,7 +100,57 @@ fn rangeinclusive_ipaddr_from_str(range: &str) -> Result<RangeInclusive<IpAddr>>
     Err(anyhow!("invalid RangeInclusive<IpAddr> value {}", range))
 }
 
+// the minimal list of networks that covers exactly the addresses of the range.
+// e.g. 11.10.1.100..=11.10.1.109 => 11.10.1.100/30, 11.10.1.104/30, 11.10.1.108/31
+pub fn rangeinclusive_to_ipnets(v: &RangeInclusive<IpAddr>) -> Result<Vec<IpNet>> {
+    let (start, end, bits) = match (v.start(), v.end()) {
+        (IpAddr::V4(s), IpAddr::V4(e)) => {
+            (u128::from(u32::from(*s)), u128::from(u32::from(*e)), 32)
+        }
+        (IpAddr::V6(s), IpAddr::V6(e)) => (u128::from(*s), u128::from(*e), 128),
+        _ => bail!("mixed address families in range {v:?}"),
+    };
+    if start > end {
+        bail!("reversed range {v:?}");
+    }
+    // addresses in a block of 2^n addresses, minus one
+    let span = |n: u32| {
+        if n >= 128 {
+            u128::MAX
+        } else {
+            (1u128 << n) - 1
+        }
+    };
+    let mut nets = Vec::new();
+    let mut cur = start;
+    loop {
+        // the largest block aligned at `cur` that does not pass `end`
+        let mut n = cur.trailing_zeros().min(bits);
+        while span(n) > end - cur {
+            n -= 1;
+        }
+        let prefix_len = (bits - n) as u8;
+        nets.push(match v.start() {
+            IpAddr::V4(_) => IpNet::V4(Ipv4Net::new(Ipv4Addr::from(cur as u32), prefix_len)?),
+            IpAddr::V6(_) => IpNet::V6(Ipv6Net::new(Ipv6Addr::from(cur), prefix_len)?),
+        });
+        match (cur + span(n)).checked_add(1) {
+            Some(next) if next <= end => cur = next,
+            _ => break,
+        }
+    }
+    Ok(nets)
+}
+
 impl NetGroup {
+    // networks covering exactly the addresses of the group.
+    pub fn to_ipnets(&self) -> Vec<IpNet> {
+        match self {
+            NetGroup::IpNet(x) => vec![*x],
+            NetGroup::IpRange((x, range)) => rangeinclusive_to_ipnets(range).unwrap_or(vec![*x]),
+        }
+    }
+
     fn network(&self) -> &IpNet {
         match self {
             NetGroup::IpNet(x) | NetGroup::IpRange((x, _)) => x,
@@ -116,6 +172,23 @@ impl NetGroup {
         }
     }
 
+    // number of addresses in the group. the smaller one is the more specific.
+    fn size(&self) -> u128 {
+        match self {
+            NetGroup::IpNet(IpNet::V4(x)) => u128::from(u32::from(x.hostmask())) + 1,
+            NetGroup::IpNet(IpNet::V6(x)) => u128::from(x.hostmask()).saturating_add(1),
+            NetGroup::IpRange((_, x)) => match (x.start(), x.end()) {
+                (IpAddr::V4(s), IpAddr::V4(e)) => {
+                    u128::from(u32::from(*e).saturating_sub(u32::from(*s))) + 1
+                }
+                (IpAddr::V6(s), IpAddr::V6(e)) => u128::from(*e)
+                    .saturating_sub(u128::from(*s))
+                    .saturating_add(1),
+                _ => u128::MAX,
+            },
+        }
+    }
+
     fn bitand(&self, netmask: IpAddr) -> Option<IpAddr> {
         match (self.network(), netmask) {
             (IpNet::V4(x), IpAddr::V4(y)) => Some(IpAddr::V4(x.addr().bitand(y))),
@@ -157,16 +230,13 @@ impl FromStr for NetGroup {
 
 impl std::fmt::Display for IpNode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?} {:?}", self.netmask, self.values)
+        write!(f, "{:?}", self.values)
     }
 }
 
 impl IpNode {
-    fn new(v: NetGroup) -> Self {
-        Self {
-            netmask: None,
-            values: vec![v],
-        }
+    fn new(v: (usize, NetGroup)) -> Self {
+        Self { values: vec![v] }
     }
 
     #[allow(unused)]
@@ -174,20 +244,18 @@ impl IpNode {
         self.values.len()
     }
 
-    fn values(&self) -> &Vec<NetGroup> {
+    fn values(&self) -> &Vec<(usize, NetGroup)> {
         &self.values
     }
-
-    fn netmask(&self) -> Option<IpAddr> {
-        self.netmask
-    }
 }
 
 impl IpMapper {
     pub fn new() -> Self {
         Self {
-            netmask: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
-            tree: HashMap::new(),
+            labels: Vec::new(),
+            indices: HashMap::new(),
+            v4: None,
+            v6: None,
         }
     }
 
@@ -195,32 +263,112 @@ impl IpMapper {
         let Ok(ipaddr) = IpAddr::from_str(ipaddr) else {
             return false;
         };
+        self.lookup(ipaddr).is_some()
+    }
+
+    // returns the label and the most specific network group containing ipaddr.
+    pub fn lookup(&self, ipaddr: IpAddr) -> Option<(&str, &NetGroup)> {
+        let tree = match ipaddr {
+            IpAddr::V4(_) => self.v4.as_ref(),
+            IpAddr::V6(_) => self.v6.as_ref(),
+        };
+        let (label, net) = tree.and_then(|t| t.detect(ipaddr))?;
+        Some((self.labels.get(*label).map_or("", String::as_str), net))
+    }
+
+    // build from (label, network group) pairs, e.g. the records of a compiled database.
+    pub fn from_entries(mut entries: Vec<(String, NetGroup)>) -> Option<IpMapper> {
+        // widest first, so no insert changes the netmask of a tree.
+        entries.sort_by_key(|(_, net)| net.prefix_len());
+        let mut mapper = IpMapper::new();
+        for (label, net) in entries {
+            mapper.insert(&label, net);
+        }
+        (mapper.v4.is_some() || mapper.v6.is_some()).then_some(mapper)
+    }
+
+    // add a network group. a group wider than every group of its address family
+    // rebuilds the tree of that family with the new netmask.
+    pub fn insert(&mut self, label: &str, net: NetGroup) {
+        let idx = match self.indices.get(label) {
+            Some(idx) => *idx,
+            None => {
+                self.labels.push(label.to_string());
+                self.indices
+                    .insert(label.to_string(), self.labels.len() - 1);
+                self.labels.len() - 1
+            }
+        };
+        let tree = self.tree_mut(&net);
+        match tree {
+            Some(tree) => tree.insert((idx, net)),
+            None => *tree = IpTree::build(vec![(idx, net)]),
+        }
+    }
+
+    // remove a network group added with the label. false if there is no such group.
+    // the netmask of the tree is kept, as a wider one still finds every group.
+    #[allow(unused)]
+    pub fn remove(&mut self, label: &str, net: &NetGroup) -> bool {
+        let Some(idx) = self.indices.get(label).copied() else {
+            return false;
+        };
+        let tree = self.tree_mut(net);
+        let Some(t) = tree.as_mut() else {
+            return false;
+        };
+        let removed = t.remove(idx, net);
+        if t.tree.is_empty() {
+            *tree = None;
+        }
+        removed
+    }
 
-        if let Some(masked) = network_by_ipaddr(ipaddr, self.netmask) {
-            return detect_by_ipnetworks(&self.tree, ipaddr, masked).is_some();
+    fn tree_mut(&mut self, net: &NetGroup) -> &mut Option<IpTree> {
+        match net.network() {
+            IpNet::V4(_) => &mut self.v4,
+            IpNet::V6(_) => &mut self.v6,
         }
-        false
     }
+}
 
-    pub fn build(patterns: &[String]) -> Option<IpMapper> {
-        let mut netgroups = Vec::new();
+// parse (label, network groups) pairs, e.g. ("servers", ["10.1.2.0/24"]), into the
+// entries `IpMapper::from_entries` takes. invalid groups are skipped with a warning.
+pub fn labeled_netgroups(groups: &[(String, Vec<String>)]) -> Vec<(String, NetGroup)> {
+    let mut entries = Vec::new();
+    for (label, patterns) in groups {
         for rule in patterns {
             let Ok(net) = NetGroup::from_str(rule.trim()) else {
                 warn!("invalid network group {rule}");
                 continue;
             };
-            netgroups.push(net.clone());
-        }
-        if netgroups.is_empty() {
-            return None;
+            entries.push((label.clone(), net));
         }
-        netgroups.sort_by_key(NetGroup::prefix_len);
-        let Some(netmask) = netgroups.first().map(|f: &NetGroup| f.netmask()) else {
-            return None;
-        };
+    }
+    entries
+}
+
+// replace each range by the exact networks covering it, e.g. for tools that only
+// understand prefixes. other and invalid patterns are kept as they are.
+pub fn exact_networks(patterns: &[String]) -> Vec<String> {
+    patterns
+        .iter()
+        .flat_map(|p| match NetGroup::from_str(p.trim()) {
+            Ok(net @ NetGroup::IpRange(_)) => {
+                net.to_ipnets().iter().map(ToString::to_string).collect()
+            }
+            _ => vec![p.clone()],
+        })
+        .collect()
+}
+
+impl IpTree {
+    fn build(mut netgroups: Vec<(usize, NetGroup)>) -> Option<IpTree> {
+        netgroups.sort_by_key(|(_, net)| net.prefix_len());
+        let netmask = netgroups.first().map(|(_, net)| net.netmask())?;
         let mut tree: HashMap<IpAddr, IpNode> = HashMap::new();
         for net in netgroups {
-            if let Some(ip) = net.bitand(netmask) {
+            if let Some(ip) = net.1.bitand(netmask) {
                 tree.entry(ip)
                     .and_modify(|e| e.values.push(net.clone()))
                     .or_insert_with(|| IpNode::new(net));
@@ -229,32 +377,61 @@ impl IpMapper {
         if tree.is_empty() {
             None
         } else {
-            Some(IpMapper { netmask, tree })
+            Some(IpTree { netmask, tree })
         }
     }
-}
 
-fn detect_by_ipnetworks(
-    tree: &HashMap<IpAddr, IpNode>,
-    ipaddr: IpAddr,
-    masked: IpAddr,
-) -> Option<&NetGroup> {
-    if let Some(node) = tree.get(&masked) {
-        for net in node.values() {
-            if net.contains(ipaddr) {
-                return Some(net);
+    fn insert(&mut self, entry: (usize, NetGroup)) {
+        if entry.1.netmask() < self.netmask {
+            let mut netgroups = self
+                .tree
+                .drain()
+                .flat_map(|(_, node)| node.values)
+                .collect::<Vec<_>>();
+            netgroups.push(entry);
+            if let Some(tree) = IpTree::build(netgroups) {
+                *self = tree;
             }
+        } else if let Some(ip) = entry.1.bitand(self.netmask) {
+            self.tree.entry(ip).or_default().values.push(entry);
         }
-        if let Some(node_netmask) = node.netmask() {
-            if let Some(masked_ip) = network_by_ipaddr(ipaddr, node_netmask) {
-                if masked != masked_ip {
-                    return detect_by_ipnetworks(tree, ipaddr, masked_ip);
-                }
-            }
+    }
+
+    fn remove(&mut self, idx: usize, net: &NetGroup) -> bool {
+        let Some(ip) = net.bitand(self.netmask) else {
+            return false;
+        };
+        let Some(node) = self.tree.get_mut(&ip) else {
+            return false;
+        };
+        let Some(pos) = node.values.iter().position(|(i, n)| *i == idx && n == net) else {
+            return false;
+        };
+        node.values.remove(pos);
+        if node.values.is_empty() {
+            self.tree.remove(&ip);
         }
+        true
     }
 
-    None
+    fn detect(&self, ipaddr: IpAddr) -> Option<&(usize, NetGroup)> {
+        let masked = network_by_ipaddr(ipaddr, self.netmask)?;
+        detect_by_ipnetworks(&self.tree, ipaddr, masked)
+    }
+}
+
+// longest-prefix match: among the groups containing ipaddr, the one with the
+// fewest addresses wins.
+fn detect_by_ipnetworks(
+    tree: &HashMap<IpAddr, IpNode>,
+    ipaddr: IpAddr,
+    masked: IpAddr,
+) -> Option<&(usize, NetGroup)> {
+    tree.get(&masked)?
+        .values()
+        .iter()
+        .filter(|(_, net)| net.contains(ipaddr))
+        .min_by_key(|(_, net)| net.size())
 }
 
 fn network_by_ipaddr(ipaddr: IpAddr, netmask: IpAddr) -> Option<IpAddr> {
@@ -264,3 +441,226 @@ fn network_by_ipaddr(ipaddr: IpAddr, netmask: IpAddr) -> Option<IpAddr> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{labeled_netgroups, IpMapper, NetGroup};
+    use std::net::IpAddr;
+
+    fn build_labeled(groups: &[(String, Vec<String>)]) -> Option<IpMapper> {
+        IpMapper::from_entries(labeled_netgroups(groups))
+    }
+
+    fn mapper(patterns: &[&str]) -> IpMapper {
+        let patterns = patterns.iter().map(ToString::to_string).collect::<Vec<_>>();
+        build_labeled(&[(String::new(), patterns)]).expect("valid network groups")
+    }
+
+    #[test]
+    fn insert_and_remove() {
+        let net = |s: &str| s.parse::<NetGroup>().unwrap();
+        let mut zones = IpMapper::new();
+        zones.insert("servers", net("10.1.2.0/24"));
+        zones.insert("voip", net("10.1.2.128/25"));
+        // wider than the tree's netmask
+        zones.insert("internal", net("10.0.0.0/8"));
+        zones.insert("internal", net("fd00::/8"));
+        let zone = |zones: &IpMapper, s: &str| {
+            zones
+                .lookup(s.parse().unwrap())
+                .map(|(zone, _)| zone.to_string())
+        };
+        assert_eq!(zone(&zones, "10.1.2.200").as_deref(), Some("voip"));
+        assert_eq!(zone(&zones, "10.1.2.5").as_deref(), Some("servers"));
+        assert_eq!(zone(&zones, "10.9.9.9").as_deref(), Some("internal"));
+        assert_eq!(zone(&zones, "fd00::1").as_deref(), Some("internal"));
+
+        assert!(!zones.remove("servers", &net("10.1.2.128/25")));
+        assert!(zones.remove("voip", &net("10.1.2.128/25")));
+        assert_eq!(zone(&zones, "10.1.2.200").as_deref(), Some("servers"));
+        assert!(zones.remove("internal", &net("fd00::/8")));
+        assert!(!zones.contains("fd00::1"));
+        assert!(zones.v6.is_none());
+    }
+
+    #[test]
+    fn dual_stack_contains() {
+        let local_net = mapper(&[
+            "10.0.0.0/8",
+            "192.168.0.0/16",
+            "fd00::/8",
+            "2001:db8::10..=2001:db8::20",
+            "172.16.0.0/12",
+        ]);
+        assert!(local_net.contains("10.1.2.3"));
+        assert!(local_net.contains("172.31.255.255"));
+        assert!(local_net.contains("192.168.10.1"));
+        assert!(!local_net.contains("8.8.8.8"));
+        assert!(local_net.contains("fd12:3456::1"));
+        assert!(local_net.contains("2001:db8::10"));
+        assert!(local_net.contains("2001:db8::20"));
+        assert!(!local_net.contains("2001:db8::21"));
+        assert!(!local_net.contains("fe80::1"));
+    }
+
+    #[test]
+    fn single_family() {
+        let v6_only = mapper(&["fd00::/8", "2001:db8::/32"]);
+        assert!(v6_only.contains("2001:db8:1::1"));
+        assert!(!v6_only.contains("10.0.0.1"));
+
+        let v4_only = mapper(&["10.0.0.0/8"]);
+        assert!(v4_only.contains("10.0.0.1"));
+        assert!(!v4_only.contains("fd00::1"));
+    }
+
+    // a range that is the widest group of its family sets the netmask of the tree
+    #[test]
+    fn ranges_alone() {
+        let v4_range = mapper(&["10.1.3.10..=10.1.3.20"]);
+        assert!(v4_range.contains("10.1.3.10"));
+        assert!(v4_range.contains("10.1.3.18"));
+        assert!(v4_range.contains("10.1.3.20"));
+        assert!(!v4_range.contains("10.1.3.21"));
+
+        let v6_range = mapper(&["2001:db8::1..=2001:db8::ff"]);
+        assert!(v6_range.contains("2001:db8::1"));
+        assert!(v6_range.contains("2001:db8::f0"));
+        assert!(!v6_range.contains("2001:db8::100"));
+
+        let mixed = mapper(&["10.0.0.0/8", "2001:db8::1..=2001:db8::ff"]);
+        assert!(mixed.contains("10.200.0.1"));
+        assert!(mixed.contains("2001:db8::ff"));
+        assert!(!mixed.contains("2001:db8::"));
+    }
+
+    #[test]
+    fn invalid_groups_are_skipped() {
+        assert!(build_labeled(&[(String::new(), vec!["not-a-network".to_string()])]).is_none());
+        assert!(!IpMapper::new().contains("10.0.0.1"));
+    }
+
+    fn labeled(ip: &str, mapper: &IpMapper) -> Option<(String, String)> {
+        let ip: IpAddr = ip.parse().unwrap();
+        mapper
+            .lookup(ip)
+            .map(|(label, net)| (label.to_string(), net.to_string()))
+    }
+
+    #[test]
+    fn longest_prefix_lookup() {
+        let zones = build_labeled(&[
+            ("office".to_string(), vec!["10.1.0.0/16".to_string()]),
+            (
+                "servers".to_string(),
+                vec![
+                    "10.1.2.0/24".to_string(),
+                    "10.1.3.10..=10.1.3.20".to_string(),
+                ],
+            ),
+            ("lab".to_string(), vec!["fd00:1::/64".to_string()]),
+            ("v6".to_string(), vec!["fd00::/8".to_string()]),
+        ])
+        .expect("valid network groups");
+
+        assert_eq!(
+            labeled("10.1.2.7", &zones),
+            Some(("servers".to_string(), "10.1.2.0/24".to_string()))
+        );
+        assert_eq!(
+            labeled("10.1.3.15", &zones),
+            Some(("servers".to_string(), "10.1.3.10..=10.1.3.20".to_string()))
+        );
+        assert_eq!(
+            labeled("10.1.3.21", &zones),
+            Some(("office".to_string(), "10.1.0.0/16".to_string()))
+        );
+        assert_eq!(
+            labeled("fd00:1::5", &zones),
+            Some(("lab".to_string(), "fd00:1::/64".to_string()))
+        );
+        assert_eq!(
+            labeled("fd00:2::5", &zones),
+            Some(("v6".to_string(), "fd00::/8".to_string()))
+        );
+        assert_eq!(labeled("10.2.0.1", &zones), None);
+    }
+
+    #[test]
+    fn range_lookup() {
+        // the range is the only group
+        let only = build_labeled(&[(
+            "servers".to_string(),
+            vec!["10.1.3.10..=10.1.3.20".to_string()],
+        )])
+        .expect("valid network groups");
+        assert_eq!(
+            labeled("10.1.3.18", &only),
+            Some(("servers".to_string(), "10.1.3.10..=10.1.3.20".to_string()))
+        );
+        assert_eq!(labeled("10.1.3.21", &only), None);
+
+        // the range is the widest group of its label and of the tree
+        let widest = build_labeled(&[
+            (
+                "servers".to_string(),
+                vec![
+                    "10.1.3.10..=10.1.3.20".to_string(),
+                    "10.1.3.16/30".to_string(),
+                ],
+            ),
+            ("db".to_string(), vec!["10.1.3.12".to_string()]),
+        ])
+        .expect("valid network groups");
+        assert_eq!(
+            labeled("10.1.3.18", &widest),
+            Some(("servers".to_string(), "10.1.3.16/30".to_string()))
+        );
+        assert_eq!(
+            labeled("10.1.3.12", &widest),
+            Some(("db".to_string(), "10.1.3.12/32".to_string()))
+        );
+        assert_eq!(
+            labeled("10.1.3.20", &widest),
+            Some(("servers".to_string(), "10.1.3.10..=10.1.3.20".to_string()))
+        );
+    }
+
+    #[test]
+    fn exact_range_lookup() {
+        let groups = [(
+            "servers".to_string(),
+            vec![
+                "10.1.3.10..=10.1.3.20".to_string(),
+                "10.1.4.0/24".to_string(),
+            ],
+        )];
+        let groups = groups.map(|(label, nets)| (label, super::exact_networks(&nets)));
+        let zones = build_labeled(&groups).expect("valid network groups");
+        assert_eq!(
+            labeled("10.1.3.13", &zones),
+            Some(("servers".to_string(), "10.1.3.12/30".to_string()))
+        );
+        assert_eq!(
+            labeled("10.1.3.20", &zones),
+            Some(("servers".to_string(), "10.1.3.20/32".to_string()))
+        );
+        assert_eq!(labeled("10.1.3.21", &zones), None);
+        assert_eq!(labeled("10.1.3.9", &zones), None);
+    }
+
+    #[test]
+    fn exact_cidrs() {
+        let range = "11.10.1.100".parse::<IpAddr>().unwrap()..="11.10.1.109".parse().unwrap();
+        let nets = super::rangeinclusive_to_ipnets(&range)
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(nets, ["11.10.1.100/30", "11.10.1.104/30", "11.10.1.108/31"]);
+        let v6 = "::".parse::<IpAddr>().unwrap()..="::ffff".parse().unwrap();
+        assert_eq!(super::rangeinclusive_to_ipnets(&v6).unwrap().len(), 1);
+        let reversed = range.end().to_owned()..=range.start().to_owned();
+        assert!(super::rangeinclusive_to_ipnets(&reversed).is_err());
+    }
+}
@@ -0,0 +1,153 @@
+use crate::net::NetGroup;
+use anyhow::{anyhow, bail, Result};
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// reader of the network group databases compiled by test-rangeinclusive
+// (`compile <list> <db>`). see its netdb.rs for the format. the intervals are not read;
+// the records and their labels are loaded into an `IpMapper`.
+const MAGIC: [u8; 4] = *b"NGDB";
+const VERSION: u16 = 3;
+const HEADER_LEN: usize = 56;
+const RECORD_LEN: usize = 40;
+const INTERVAL_LEN: usize = 32;
+
+const KIND_V4_NET: u8 = 0;
+const KIND_V6_NET: u8 = 1;
+const KIND_V4_RANGE: u8 = 2;
+const KIND_V6_RANGE: u8 = 3;
+
+// the network groups of a compiled database with their labels. "" for no label.
+pub fn load(path: &str) -> Result<Vec<(String, NetGroup)>> {
+    let bytes = std::fs::read(path).map_err(|e| anyhow!("fail to read {path}. {e}"))?;
+    parse(&bytes).map_err(|e| anyhow!("fail to load {path}. {e}"))
+}
+
+fn parse(bytes: &[u8]) -> Result<Vec<(String, NetGroup)>> {
+    if bytes.len() < HEADER_LEN || bytes[..4] != MAGIC {
+        bail!("not a network group database");
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != VERSION {
+        bail!("unsupported database version {version}. expected {VERSION}");
+    }
+    let record_len = u16::from_le_bytes([bytes[6], bytes[7]]);
+    if usize::from(record_len) != RECORD_LEN {
+        bail!("invalid record length {record_len}");
+    }
+    let len = usize::try_from(read_u64(bytes, 8))?;
+    let intervals =
+        usize::try_from(read_u64(bytes, 16))?.checked_add(usize::try_from(read_u64(bytes, 24))?);
+    let labels_len = usize::try_from(read_u64(bytes, 32))?;
+    let body = len.checked_mul(RECORD_LEN).and_then(|records| {
+        intervals?
+            .checked_mul(INTERVAL_LEN)?
+            .checked_add(records)?
+            .checked_add(labels_len)
+    });
+    if body.and_then(|n| n.checked_add(HEADER_LEN)) != Some(bytes.len()) {
+        bail!("database is truncated or has trailing data");
+    }
+    if fnv1a(&bytes[HEADER_LEN..]) != read_u64(bytes, 48) {
+        bail!("checksum mismatch");
+    }
+
+    let labels = &bytes[bytes.len() - labels_len..];
+    let (records, _) = bytes[HEADER_LEN..HEADER_LEN + len * RECORD_LEN].as_chunks::<RECORD_LEN>();
+    records
+        .iter()
+        .enumerate()
+        .map(|(idx, record)| {
+            decode(record, labels).map_err(|e| anyhow!("invalid record {idx}. {e}"))
+        })
+        .collect()
+}
+
+fn decode(record: &[u8; RECORD_LEN], labels: &[u8]) -> Result<(String, NetGroup)> {
+    let (kind, prefix_len) = (record[0], record[1]);
+    let v4 = kind == KIND_V4_NET || kind == KIND_V4_RANGE;
+    let addr = |bytes: &[u8]| -> IpAddr {
+        if v4 {
+            IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]))
+        } else {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&bytes[..16]);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+    };
+    let start = addr(&record[2..18]);
+    let net = match start {
+        IpAddr::V4(x) => IpNet::V4(Ipv4Net::new(x, prefix_len)?),
+        IpAddr::V6(x) => IpNet::V6(Ipv6Net::new(x, prefix_len)?),
+    };
+    let net = match kind {
+        KIND_V4_NET | KIND_V6_NET => NetGroup::IpNet(net),
+        KIND_V4_RANGE | KIND_V6_RANGE => {
+            let end = addr(&record[18..34]);
+            if end < start {
+                bail!("reversed range {start}..={end}");
+            }
+            NetGroup::IpRange((net.trunc(), start..=end))
+        }
+        _ => bail!("unknown record kind {kind}"),
+    };
+
+    let offset = usize::try_from(u32::from_le_bytes([
+        record[34], record[35], record[36], record[37],
+    ]))?;
+    let len = usize::from(u16::from_le_bytes([record[38], record[39]]));
+    let label = offset
+        .checked_add(len)
+        .and_then(|end| labels.get(offset..end))
+        .ok_or_else(|| anyhow!("label is out of the label table"))?;
+    let label = std::str::from_utf8(label).map_err(|e| anyhow!("label is not utf-8. {e}"))?;
+    Ok((label.to_string(), net))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(buf)
+}
+
+// 64-bit FNV-1a
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, HEADER_LEN};
+
+    // compiled from sample_zones.list by test-rangeinclusive
+    const SAMPLE: &[u8] = include_bytes!("../sample_zones.ngdb");
+
+    #[test]
+    fn sample_database() {
+        let entries = parse(SAMPLE)
+            .unwrap()
+            .into_iter()
+            .map(|(label, net)| (label, net.to_string()))
+            .collect::<Vec<_>>();
+        let entry = |label: &str, net: &str| (label.to_string(), net.to_string());
+        assert_eq!(
+            entries,
+            [
+                entry("voip", "10.0.100.128/25"),
+                entry("partners", "198.51.100.10..=198.51.100.20"),
+                entry("partners", "203.0.113.0/24"),
+                entry("partners", "2001:db8:100::/48"),
+            ]
+        );
+
+        let mut flipped = SAMPLE.to_vec();
+        flipped[HEADER_LEN + 3] ^= 1;
+        assert!(parse(&flipped)
+            .unwrap_err()
+            .to_string()
+            .contains("checksum"));
+        assert!(parse(&SAMPLE[..SAMPLE.len() - 1]).is_err());
+    }
+}
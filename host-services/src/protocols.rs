@@ -0,0 +1,163 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader},
+};
+
+// used when /etc/protocols is not available.
+const BUILTIN_PROTOCOLS: [(&str, u8); 15] = [
+    ("icmp", 1),
+    ("igmp", 2),
+    ("ipencap", 4),
+    ("tcp", 6),
+    ("udp", 17),
+    ("dccp", 33),
+    ("ipv6", 41),
+    ("gre", 47),
+    ("esp", 50),
+    ("ah", 51),
+    ("ipv6-icmp", 58),
+    ("icmpv6", 58),
+    ("ospf", 89),
+    ("vrrp", 112),
+    ("sctp", 132),
+];
+
+// protocols whose flows carry port numbers.
+const PORT_PROTOCOLS: [u8; 4] = [6, 17, 33, 132];
+
+#[derive(Clone, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct Protocol {
+    pub number: u8,
+    pub name: String,
+}
+
+impl Protocol {
+    pub fn has_ports(&self) -> bool {
+        has_ports(self.number)
+    }
+}
+
+// whether flows of the protocol number carry port numbers.
+pub fn has_ports(number: u8) -> bool {
+    PORT_PROTOCOLS.contains(&number)
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ProtocolTable {
+    by_number: HashMap<u8, String>,
+    by_name: HashMap<String, u8>,
+}
+
+// /etc/protocols 파일을 읽어서 프로토콜 번호와 이름을 변환하는 ProtocolTable 을 만든다.
+// 파일을 읽을 수 없으면 내장된 목록을 사용한다.
+pub fn build(path: &str) -> ProtocolTable {
+    let mut table = ProtocolTable::builtin();
+    if let Ok(file) = std::fs::File::open(path) {
+        table.read_etc_protocols(BufReader::new(file));
+    }
+    table
+}
+
+impl ProtocolTable {
+    pub fn builtin() -> Self {
+        let mut table = Self::default();
+        for (name, number) in BUILTIN_PROTOCOLS {
+            table.insert(name, number, &[]);
+        }
+        table
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_number.len()
+    }
+
+    fn insert(&mut self, name: &str, number: u8, aliases: &[&str]) {
+        self.by_number
+            .entry(number)
+            .or_insert_with(|| name.to_lowercase());
+        for name in std::iter::once(&name).chain(aliases) {
+            self.by_name.insert(name.to_lowercase(), number);
+        }
+    }
+
+    // name number [aliases ...] [# comment]
+    pub fn read_etc_protocols<R: BufRead>(&mut self, rdr: R) {
+        for line in rdr.lines().map_while(Result::ok) {
+            let s = line.find('#').map_or(line.as_str(), |idx| &line[..idx]);
+            let mut fields = s.split_whitespace();
+            let Some(name) = fields.next() else {
+                continue;
+            };
+            let Some(number) = fields.next().and_then(|n| n.parse::<u8>().ok()) else {
+                continue;
+            };
+            let aliases = fields.collect::<Vec<_>>();
+            self.insert(name, number, &aliases);
+        }
+    }
+
+    // protocol given as a number ("6") or as a name ("tcp").
+    pub fn get(&self, s: &str) -> Option<Protocol> {
+        self.number(s).map(|n| self.protocol(n))
+    }
+
+    // same as `get`, without allocating the protocol name.
+    pub fn number(&self, s: &str) -> Option<u8> {
+        if let Ok(n) = s.parse::<u8>() {
+            return Some(n);
+        }
+        match self.by_name.get(s) {
+            Some(n) => Some(*n),
+            None => self.by_name.get(&s.to_lowercase()).copied(),
+        }
+    }
+
+    pub fn protocol(&self, number: u8) -> Protocol {
+        let name = self
+            .by_number
+            .get(&number)
+            .cloned()
+            .unwrap_or_else(|| number.to_string());
+        Protocol { number, name }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ProtocolTable;
+
+    #[test]
+    fn etc_protocols() {
+        let mut table = ProtocolTable::default();
+        table.read_etc_protocols(
+            "ip\t0\tIP\t\t# internet protocol\nhopopt\t0\tHOPOPT\nicmp\t1\tICMP\n# comment\nipv6-icmp 58\tIPv6-ICMP\n"
+                .as_bytes(),
+        );
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.get("ICMP").map(|p| p.number), Some(1));
+        assert_eq!(table.get("0").map(|p| p.name), Some("ip".to_string()));
+        assert_eq!(table.get("hopopt").map(|p| p.name), Some("ip".to_string()));
+        assert_eq!(
+            table.get("58").map(|p| p.name),
+            Some("ipv6-icmp".to_string())
+        );
+        assert_eq!(table.get("200").map(|p| p.name), Some("200".to_string()));
+        assert!(table.get("bogus").is_none());
+    }
+
+    #[test]
+    fn builtin_fallback() {
+        let table = super::build("/nonexistent/protocols");
+        let esp = table.get("esp").unwrap();
+        assert_eq!(esp.number, 50);
+        assert!(!esp.has_ports());
+        assert!(table.get("17").unwrap().has_ports());
+    }
+}
@@ -0,0 +1,241 @@
+use crate::protocols::Protocol;
+use chrono::{DateTime, TimeDelta, Utc};
+use std::{
+    collections::{BTreeSet, HashMap},
+    hash::Hash,
+};
+
+#[derive(Clone, Debug)]
+pub struct ScanConfig {
+    pub window: TimeDelta,
+    // distinct ports on one host to call it a vertical scan.
+    pub min_ports: usize,
+    // distinct hosts on one port to call it a horizontal sweep.
+    pub min_hosts: usize,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ScanKind {
+    // one source, one target host, many ports
+    Vertical,
+    // one source, one port, many target hosts
+    Horizontal,
+}
+
+impl std::fmt::Display for ScanKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScanKind::Vertical => write!(f, "vertical"),
+            ScanKind::Horizontal => write!(f, "horizontal"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Scan {
+    pub scanner: String,
+    pub kind: ScanKind,
+    pub proto: Protocol,
+    pub targets: Vec<String>,
+    pub ports: Vec<u16>,
+    pub first: DateTime<Utc>,
+    pub last: DateTime<Utc>,
+    pub sessions: usize,
+}
+
+// observations in arrival order; sorted by time when detecting.
+type Events<K> = Vec<(DateTime<Utc>, K)>;
+
+#[derive(Debug, Default)]
+pub struct ScanDetector {
+    // (source, destination, protocol) => (time, destination port)
+    vertical: HashMap<(String, String, Protocol), Events<u16>>,
+    // (source, destination port, protocol) => (time, destination)
+    horizontal: HashMap<(String, u16, Protocol), Events<String>>,
+}
+
+impl ScanDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, src: &str, dest: &str, port: u16, proto: &Protocol, t: DateTime<Utc>) {
+        self.vertical
+            .entry((src.to_string(), dest.to_string(), proto.clone()))
+            .or_default()
+            .push((t, port));
+        self.horizontal
+            .entry((src.to_string(), port, proto.clone()))
+            .or_default()
+            .push((t, dest.to_string()));
+    }
+
+    pub fn detect(mut self, conf: &ScanConfig) -> Vec<Scan> {
+        let mut scans = Vec::new();
+        for ((src, dest, proto), mut events) in self.vertical.drain() {
+            let Some((start, end)) = busiest_window(&mut events, conf.window, conf.min_ports)
+            else {
+                continue;
+            };
+            let window = &events[start..end];
+            scans.push(Scan {
+                scanner: src,
+                kind: ScanKind::Vertical,
+                proto,
+                targets: vec![dest],
+                ports: window
+                    .iter()
+                    .map(|(_, p)| *p)
+                    .collect::<BTreeSet<_>>()
+                    .into_iter()
+                    .collect(),
+                first: window[0].0,
+                last: window[window.len() - 1].0,
+                sessions: window.len(),
+            });
+        }
+        for ((src, port, proto), mut events) in self.horizontal.drain() {
+            let Some((start, end)) = busiest_window(&mut events, conf.window, conf.min_hosts)
+            else {
+                continue;
+            };
+            let window = &events[start..end];
+            scans.push(Scan {
+                scanner: src,
+                kind: ScanKind::Horizontal,
+                proto,
+                targets: window
+                    .iter()
+                    .map(|(_, d)| d.clone())
+                    .collect::<BTreeSet<_>>()
+                    .into_iter()
+                    .collect(),
+                ports: vec![port],
+                first: window[0].0,
+                last: window[window.len() - 1].0,
+                sessions: window.len(),
+            });
+        }
+        scans.sort_by(|a, b| (&a.scanner, a.first).cmp(&(&b.scanner, b.first)));
+        scans
+    }
+}
+
+// sort the events by time and find the window with the most distinct values.
+// returns the [start, end) index of the window if it has at least `min_distinct` values.
+fn busiest_window<K: Clone + Eq + Hash>(
+    events: &mut [(DateTime<Utc>, K)],
+    window: TimeDelta,
+    min_distinct: usize,
+) -> Option<(usize, usize)> {
+    let distinct = events
+        .iter()
+        .map(|(_, k)| k)
+        .collect::<std::collections::HashSet<_>>()
+        .len();
+    if distinct < min_distinct {
+        return None;
+    }
+    events.sort_by_key(|(t, _)| *t);
+
+    let mut counts: HashMap<K, usize> = HashMap::new();
+    let mut best: Option<(usize, usize, usize)> = None;
+    let mut start = 0;
+    for end in 0..events.len() {
+        *counts.entry(events[end].1.clone()).or_default() += 1;
+        while events[end].0 - events[start].0 > window {
+            let key = &events[start].1;
+            if let Some(cnt) = counts.get_mut(key) {
+                *cnt -= 1;
+                if *cnt == 0 {
+                    counts.remove(key);
+                }
+            }
+            start += 1;
+        }
+        if best.is_none_or(|(_, _, n)| counts.len() > n) {
+            best = Some((start, end + 1, counts.len()));
+        }
+    }
+    best.filter(|(_, _, n)| *n >= min_distinct)
+        .map(|(start, end, _)| (start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ScanConfig, ScanDetector, ScanKind};
+    use crate::protocols::Protocol;
+    use chrono::{DateTime, TimeDelta, Utc};
+
+    fn tcp() -> Protocol {
+        Protocol {
+            number: 6,
+            name: "tcp".to_string(),
+        }
+    }
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000 + secs, 0).unwrap()
+    }
+
+    fn conf() -> ScanConfig {
+        ScanConfig {
+            window: TimeDelta::seconds(60),
+            min_ports: 10,
+            min_hosts: 10,
+        }
+    }
+
+    #[test]
+    fn vertical_scan() {
+        let mut detector = ScanDetector::new();
+        for port in 1..=20u16 {
+            detector.insert("203.0.113.5", "10.0.0.1", port, &tcp(), at(i64::from(port)));
+        }
+        // slow probes outside the window
+        for port in 100..105u16 {
+            detector.insert(
+                "203.0.113.6",
+                "10.0.0.1",
+                port,
+                &tcp(),
+                at(i64::from(port) * 100),
+            );
+        }
+        let scans = detector.detect(&conf());
+        assert_eq!(scans.len(), 1);
+        let scan = &scans[0];
+        assert_eq!(scan.kind, ScanKind::Vertical);
+        assert_eq!(scan.scanner, "203.0.113.5");
+        assert_eq!(scan.targets, vec!["10.0.0.1"]);
+        assert_eq!(scan.ports.len(), 20);
+        assert_eq!(scan.last - scan.first, TimeDelta::seconds(19));
+    }
+
+    #[test]
+    fn horizontal_sweep() {
+        let sweep = || {
+            let mut detector = ScanDetector::new();
+            for host in 0..30 {
+                let dest = format!("10.0.1.{host}");
+                detector.insert("10.0.0.99", &dest, 445, &tcp(), at(host * 10));
+            }
+            detector
+        };
+        let scans = sweep().detect(&ScanConfig {
+            min_hosts: 5,
+            ..conf()
+        });
+        assert_eq!(scans.len(), 1);
+        assert_eq!(scans[0].kind, ScanKind::Horizontal);
+        assert_eq!(scans[0].ports, vec![445]);
+        // 60 seconds hold 7 probes 10 seconds apart
+        assert_eq!(scans[0].targets.len(), 7);
+        assert!(sweep()
+            .detect(&ScanConfig {
+                min_hosts: 8,
+                ..conf()
+            })
+            .is_empty());
+    }
+}
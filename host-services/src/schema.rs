@@ -0,0 +1,455 @@
+use crate::input;
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, NaiveDateTime, TimeDelta, Utc};
+use csv::StringRecord;
+use std::{
+    io::{BufRead, Read},
+    str::FromStr,
+};
+
+// column names accepted for each field in zeek `#fields` and csv header rows.
+const TIMESTAMP_NAMES: [&str; 5] = ["ts", "timestamp", "time", "start_time", "stime"];
+const END_TIME_NAMES: [&str; 5] = ["end_time", "etime", "last_time", "end", "last"];
+const DURATION_NAMES: [&str; 3] = ["duration", "dur", "elapsed"];
+const SRC_NAMES: [&str; 6] = [
+    "src_addr",
+    "src_ip",
+    "source_ip",
+    "src",
+    "id.orig_h",
+    "saddr",
+];
+const DEST_NAMES: [&str; 7] = [
+    "dst_addr",
+    "dst_ip",
+    "destination_ip",
+    "dst",
+    "dest",
+    "id.resp_h",
+    "daddr",
+];
+const DEST_PORT_NAMES: [&str; 5] = [
+    "dst_port",
+    "dest_port",
+    "destination_port",
+    "dport",
+    "id.resp_p",
+];
+const PROTO_NAMES: [&str; 2] = ["proto", "protocol"];
+const SENT_BYTES_NAMES: [&str; 4] = ["sent_bytes", "orig_bytes", "bytes_out", "src_bytes"];
+const SERVICE_NAMES: [&str; 3] = ["service", "service_name", "app"];
+const SENT_PACKETS_NAMES: [&str; 4] = ["sent_packets", "orig_pkts", "pkts_out", "src_pkts"];
+const RECEIVED_PACKETS_NAMES: [&str; 5] = [
+    "recv_packets",
+    "received_packets",
+    "resp_pkts",
+    "pkts_in",
+    "dst_pkts",
+];
+const RECEIVED_BYTES_NAMES: [&str; 5] = [
+    "recv_bytes",
+    "received_bytes",
+    "resp_bytes",
+    "bytes_in",
+    "dst_bytes",
+];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    // tab separated Giganto conn export. a header row, if any, is skipped.
+    Giganto,
+    // zeek conn.log with `#fields` header.
+    Zeek,
+    // comma separated values with a header row.
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "giganto" => Ok(Format::Giganto),
+            "zeek" => Ok(Format::Zeek),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("invalid input format {s}")),
+        }
+    }
+}
+
+impl Format {
+    // record reader for the lines after the header. `open` reads the header.
+    pub fn reader<R: Read>(self, rdr: R) -> csv::Reader<R> {
+        let mut builder = csv::ReaderBuilder::new();
+        builder.has_headers(false).flexible(true);
+        match self {
+            Format::Giganto => builder.delimiter(b'\t'),
+            Format::Zeek => builder.delimiter(b'\t').comment(Some(b'#')).quoting(false),
+            Format::Csv => &mut builder,
+        };
+        builder.from_reader(rdr)
+    }
+}
+
+// call `f` with every record of the input, reusing one record buffer.
+// records that do not parse, e.g. invalid utf-8, are skipped. i/o errors end the input.
+pub fn for_each_record<R: Read>(
+    mut rdr: csv::Reader<R>,
+    mut f: impl FnMut(&StringRecord),
+) -> csv::Result<()> {
+    let mut rec = StringRecord::new();
+    loop {
+        match rdr.read_record(&mut rec) {
+            Ok(true) => f(&rec),
+            Ok(false) => return Ok(()),
+            Err(e) if !e.is_io_error() => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// column index of each field used by host-services.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Schema {
+    timestamp: Option<usize>,
+    end_time: Option<usize>,
+    // session length in seconds, as zeek writes it
+    duration: Option<usize>,
+    src: usize,
+    dest: usize,
+    dest_port: usize,
+    proto: usize,
+    service: Option<usize>,
+    sent_bytes: Option<usize>,
+    received_bytes: Option<usize>,
+    sent_packets: Option<usize>,
+    received_packets: Option<usize>,
+}
+
+// one flow taken from a record by a schema.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Flow<'a> {
+    // parsed by `time` only when needed.
+    pub timestamp: Option<&'a str>,
+    // parsed by `duration` only when needed.
+    pub end_time: Option<&'a str>,
+    pub duration: Option<&'a str>,
+    pub src: &'a str,
+    pub dest: &'a str,
+    pub dest_port: u16,
+    pub proto: &'a str,
+    // service name detected by the sensor, if any.
+    pub service: Option<&'a str>,
+    pub sent_bytes: u64,
+    pub received_bytes: u64,
+    pub sent_packets: u64,
+    pub received_packets: u64,
+}
+
+impl Schema {
+    // fields: timestamp, source name, source ip address, source port, destination ip address,
+    // destination port, protocol, session end time, service name, sent bytes, received bytes,
+    // send packets, received packets
+    pub fn giganto() -> Self {
+        Self {
+            timestamp: Some(0),
+            end_time: Some(7),
+            duration: None,
+            src: 2,
+            dest: 4,
+            dest_port: 5,
+            proto: 6,
+            service: Some(8),
+            sent_bytes: Some(9),
+            received_bytes: Some(10),
+            sent_packets: Some(11),
+            received_packets: Some(12),
+        }
+    }
+
+    pub fn by_names<'a, I>(names: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let names = names
+            .into_iter()
+            .map(|n| n.trim().to_lowercase())
+            .collect::<Vec<_>>();
+        let find = |aliases: &[&str]| names.iter().position(|n| aliases.contains(&n.as_str()));
+        let required =
+            |aliases: &[&str]| find(aliases).ok_or_else(|| anyhow!("no column for {}", aliases[0]));
+        Ok(Self {
+            timestamp: find(&TIMESTAMP_NAMES),
+            end_time: find(&END_TIME_NAMES),
+            duration: find(&DURATION_NAMES),
+            src: required(&SRC_NAMES)?,
+            dest: required(&DEST_NAMES)?,
+            dest_port: required(&DEST_PORT_NAMES)?,
+            proto: required(&PROTO_NAMES)?,
+            service: find(&SERVICE_NAMES),
+            sent_bytes: find(&SENT_BYTES_NAMES),
+            received_bytes: find(&RECEIVED_BYTES_NAMES),
+            sent_packets: find(&SENT_PACKETS_NAMES),
+            received_packets: find(&RECEIVED_PACKETS_NAMES),
+        })
+    }
+
+    // "#fields\tts\tuid\tid.orig_h..." line of zeek log header.
+    pub fn zeek(fields: &str) -> Result<Self> {
+        let Some(names) = fields.strip_prefix("#fields") else {
+            bail!("invalid zeek #fields header");
+        };
+        Self::by_names(names.split('\t').filter(|n| !n.is_empty()))
+    }
+
+    // open the file ("-" for standard input) and read its header if any.
+    // returns the schema and the input positioned at the first record.
+    pub fn open(format: Format, path: &str) -> Result<(Self, Box<dyn BufRead + Send>)> {
+        let mut input = input::open(path)?;
+        let schema = match format {
+            Format::Giganto => {
+                // exports are written without a header, but one added by hand or by other
+                // tools was skipped before. a row whose destination port is not a number is
+                // taken as a header; a data row is put back in front of the input.
+                let mut line = String::new();
+                input.read_line(&mut line)?;
+                let is_header = line
+                    .split('\t')
+                    .nth(5)
+                    .is_some_and(|port| port.trim().parse::<u16>().is_err());
+                if !is_header {
+                    input = Box::new(std::io::Cursor::new(line.into_bytes()).chain(input));
+                }
+                Self::giganto()
+            }
+            Format::Zeek => {
+                let mut fields = None;
+                while input.fill_buf()?.starts_with(b"#") {
+                    let mut line = String::new();
+                    input.read_line(&mut line)?;
+                    if line.starts_with("#fields") {
+                        fields = Some(line.trim_end_matches(['\r', '\n']).to_string());
+                    }
+                }
+                let fields = fields.ok_or_else(|| anyhow!("no #fields header in {path}"))?;
+                Self::zeek(&fields)?
+            }
+            Format::Csv => {
+                let mut line = String::new();
+                input.read_line(&mut line)?;
+                let mut rdr = format.reader(line.as_bytes());
+                let headers = rdr
+                    .records()
+                    .next()
+                    .ok_or_else(|| anyhow!("no header row in {path}"))??;
+                Self::by_names(headers.iter())?
+            }
+        };
+        Ok((schema, input))
+    }
+
+    pub fn flow<'a>(&self, rec: &'a StringRecord) -> Option<Flow<'a>> {
+        let count = |idx: Option<usize>| {
+            idx.and_then(|i| rec.get(i))
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or_default()
+        };
+        Some(Flow {
+            timestamp: self.timestamp.and_then(|i| rec.get(i)),
+            end_time: self.end_time.and_then(|i| rec.get(i)),
+            duration: self.duration.and_then(|i| rec.get(i)),
+            src: rec.get(self.src)?,
+            dest: rec.get(self.dest)?,
+            dest_port: rec
+                .get(self.dest_port)
+                .map(|p| p.parse::<u16>().unwrap_or_default())?,
+            proto: rec.get(self.proto)?,
+            service: self
+                .service
+                .and_then(|i| rec.get(i))
+                .filter(|v| !v.is_empty() && *v != "-"),
+            sent_bytes: count(self.sent_bytes),
+            received_bytes: count(self.received_bytes),
+            sent_packets: count(self.sent_packets),
+            received_packets: count(self.received_packets),
+        })
+    }
+}
+
+impl Flow<'_> {
+    pub fn time(&self) -> Option<DateTime<Utc>> {
+        self.timestamp.and_then(parse_timestamp)
+    }
+
+    // the duration column, or the time from the start to the end of the session.
+    // `None` if neither is recorded or the end is before the start.
+    pub fn duration(&self) -> Option<TimeDelta> {
+        if let Some(secs) = self.duration.and_then(|d| d.parse::<f64>().ok()) {
+            return (secs.is_finite() && secs >= 0.0)
+                .then(|| TimeDelta::microseconds((secs * 1e6) as i64));
+        }
+        let elapsed = parse_timestamp(self.end_time?)? - self.time()?;
+        (elapsed >= TimeDelta::zero()).then_some(elapsed)
+    }
+}
+
+// rfc3339, "2023-01-01 12:00:00.123", epoch seconds ("1700000000.123456"),
+// epoch milliseconds or epoch nanoseconds.
+pub fn parse_timestamp(s: &str) -> Option<DateTime<Utc>> {
+    let s = s.trim();
+    // epoch forms first. they are the cheapest to try. the unit is told by the number
+    // of digits: seconds, milliseconds, microseconds (zeek and json exports) or nanoseconds.
+    if let Ok(n) = s.parse::<i64>() {
+        return match s.trim_start_matches('-').len() {
+            0..=11 => DateTime::from_timestamp(n, 0),
+            12..=14 => DateTime::from_timestamp_millis(n),
+            15..=17 => DateTime::from_timestamp_micros(n),
+            _ => Some(DateTime::from_timestamp_nanos(n)),
+        };
+    }
+    if let Some((secs, frac)) = s.split_once('.') {
+        if let Ok(secs) = secs.parse::<i64>() {
+            let nanos = format!("{frac:0<9}").get(..9)?.parse::<u32>().ok()?;
+            return DateTime::from_timestamp(secs, nanos);
+        }
+    }
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Some(t.with_timezone(&Utc));
+    }
+    for fmt in ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"] {
+        if let Ok(t) = NaiveDateTime::parse_from_str(s, fmt) {
+            return Some(t.and_utc());
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_timestamp, Format, Schema};
+    use csv::StringRecord;
+
+    #[test]
+    fn zeek_fields() {
+        let fields = "#fields\tts\tuid\tid.orig_h\tid.orig_p\tid.resp_h\tid.resp_p\tproto\tservice\tduration\torig_bytes\tresp_bytes";
+        let schema = Schema::zeek(fields).unwrap();
+        let rec = StringRecord::from(vec![
+            "1700000000.0",
+            "C1",
+            "10.0.0.1",
+            "50000",
+            "10.0.0.2",
+            "53",
+            "udp",
+            "dns",
+            "0.1",
+            "40",
+            "-",
+        ]);
+        let flow = schema.flow(&rec).unwrap();
+        assert_eq!(flow.src, "10.0.0.1");
+        assert_eq!(flow.dest, "10.0.0.2");
+        assert_eq!(flow.dest_port, 53);
+        assert_eq!(flow.proto, "udp");
+        assert_eq!(flow.sent_bytes, 40);
+        assert_eq!(flow.received_bytes, 0);
+        assert_eq!(flow.service, Some("dns"));
+        assert_eq!(flow.sent_packets, 0);
+        assert_eq!(flow.time().map(|t| t.timestamp()), Some(1_700_000_000));
+        assert_eq!(flow.duration(), Some(chrono::TimeDelta::milliseconds(100)));
+    }
+
+    #[test]
+    fn giganto_end_time() {
+        let rec = StringRecord::from(
+            "2023-01-01T00:00:00Z\ts\t10.0.0.1\t5555\t10.0.0.2\t22\t6\t2023-01-01T00:01:30.5Z\tssh\t1\t2\t1\t1"
+                .split('\t')
+                .collect::<Vec<_>>(),
+        );
+        let flow = Schema::giganto().flow(&rec).unwrap();
+        assert_eq!(
+            flow.duration(),
+            Some(chrono::TimeDelta::milliseconds(90_500))
+        );
+        let rec = StringRecord::from(vec![
+            "2023-01-01T00:00:00Z",
+            "s",
+            "a",
+            "1",
+            "b",
+            "2",
+            "6",
+            "-",
+        ]);
+        assert_eq!(Schema::giganto().flow(&rec).unwrap().duration(), None);
+    }
+
+    #[test]
+    fn giganto_header_row() {
+        let row = "2023-01-01T00:00:00Z\ts\t10.0.0.1\t5555\t10.0.0.2\t22\t6\t-\tssh\t1\t2\t1\t1\n";
+        let header = "timestamp\tsource\tsrc_addr\tsrc_port\tdst_addr\tdst_port\tproto\tend_time\tservice\tsent_bytes\trecv_bytes\tsent_pkts\trecv_pkts\n";
+        let dir = std::env::temp_dir();
+        for (name, contents) in [
+            ("plain", row.to_string()),
+            ("header", format!("{header}{row}")),
+        ] {
+            let path = dir.join(format!("giganto-{name}-{}.log", std::process::id()));
+            std::fs::write(&path, &contents).unwrap();
+            let (schema, input) = Schema::open(Format::Giganto, path.to_str().unwrap()).unwrap();
+            let records = Format::Giganto
+                .reader(input)
+                .records()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(records.len(), 1, "{name}");
+            assert_eq!(schema.flow(&records[0]).unwrap().dest_port, 22);
+        }
+    }
+
+    #[test]
+    fn csv_header_names() {
+        let schema = Schema::by_names(["Protocol", "SRC_IP", "dst_ip", "dst_port"]).unwrap();
+        let rec = StringRecord::from(vec!["6", "10.0.0.1", "10.0.0.2", "443"]);
+        let flow = schema.flow(&rec).unwrap();
+        assert_eq!(flow.proto, "6");
+        assert_eq!(flow.dest_port, 443);
+        assert!(Schema::by_names(["src_ip", "dst_ip"]).is_err());
+    }
+
+    #[test]
+    fn format_from_str() {
+        assert_eq!("Zeek".parse::<Format>(), Ok(Format::Zeek));
+        assert!("json".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn timestamps() {
+        let expected = "2023-11-14T22:13:20Z".parse().ok();
+        assert_eq!(parse_timestamp("2023-11-14T22:13:20Z"), expected);
+        assert_eq!(parse_timestamp("2023-11-14T22:13:20+00:00"), expected);
+        assert_eq!(parse_timestamp("2023-11-14 22:13:20"), expected);
+        assert_eq!(parse_timestamp("1700000000"), expected);
+        assert_eq!(parse_timestamp("1700000000000"), expected);
+        assert_eq!(parse_timestamp("1700000000000000"), expected);
+        assert_eq!(parse_timestamp("1700000000000000000"), expected);
+        let subsec =
+            |s: &str| parse_timestamp(s).map(|t| (t.timestamp(), t.timestamp_subsec_nanos()));
+        assert_eq!(subsec("1700000000"), Some((1_700_000_000, 0)));
+        assert_eq!(subsec("1700000000123"), Some((1_700_000_000, 123_000_000)));
+        assert_eq!(
+            subsec("1700000000123456"),
+            Some((1_700_000_000, 123_456_000))
+        );
+        assert_eq!(
+            subsec("1700000000123456789"),
+            Some((1_700_000_000, 123_456_789))
+        );
+        // the shortest microsecond epoch
+        assert_eq!(subsec("100000000000000"), Some((100_000_000, 0)));
+        assert_eq!(
+            parse_timestamp("1700000000.5").map(|t| t.timestamp_subsec_millis()),
+            Some(500)
+        );
+        assert!(parse_timestamp("-").is_none());
+    }
+}
@@ -1,31 +1,275 @@
-use anyhow::Result;
-use std::{collections::HashMap, io::BufRead, io::BufReader};
-
-const REGEX_SERVICE: &str = r"^([a-zA-Z0-9\-]+)\s+(\d+)/(tcp|udp)\s*";
-
-// /etc/services 파일을 읽어서 포트 번호를 서비스 이름으로 변환하는 함수를 구현한다.
-pub fn build(path: &str) -> Result<HashMap<String, String>> {
-    let re = regex::Regex::new(REGEX_SERVICE)?;
-    let file = std::fs::File::open(path)?;
-    let rdr = BufReader::new(file);
-    let mut services = std::collections::HashMap::new();
-    for line in rdr.lines().flatten() {
-        if line.starts_with('#') || line.trim().is_empty() {
-            continue;
+use anyhow::{anyhow, Result};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io::{BufRead, BufReader, Read},
+    ops::RangeInclusive,
+};
+
+const REGEX_SERVICE: &str =
+    r"^([a-zA-Z0-9\-_.+/]+)\s+(\d+)/(tcp|udp|sctp|dccp)((?:\s+[a-zA-Z0-9\-_.+/]+)*)\s*$";
+
+// IANA service-names-port-numbers.csv columns
+const IANA_SERVICE_NAME: &str = "Service Name";
+const IANA_PORT_NUMBER: &str = "Port Number";
+const IANA_TRANSPORT_PROTOCOL: &str = "Transport Protocol";
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Service {
+    pub name: String,
+    // `None` for the names IANA registered without a port
+    pub ports: Option<RangeInclusive<u16>>,
+    pub proto: String,
+    pub aliases: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct ServiceRegistry {
+    services: Vec<Service>,
+    by_port: HashMap<(u16, String), usize>,
+    // services registered for a port range, e.g. x11 6000-6063/tcp.
+    ranges: Vec<usize>,
+    // lowercase names and aliases
+    by_name: HashMap<String, usize>,
+}
+
+// /etc/services 파일을 읽어서 포트 번호를 서비스 이름으로 변환하는 ServiceRegistry 를 만든다.
+pub fn build(path: &str) -> Result<ServiceRegistry> {
+    let mut registry = ServiceRegistry::new();
+    registry.read_etc_services(BufReader::new(std::fs::File::open(path)?))?;
+    Ok(registry)
+}
+
+impl ServiceRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.services.len()
+    }
+
+    // the first service registered for a port, and for a name or alias, is kept.
+    // so /etc/services should be read before the IANA registry to take precedence.
+    pub fn insert(&mut self, service: Service) {
+        let idx = self.services.len();
+        match &service.ports {
+            Some(ports) if ports.start() == ports.end() => {
+                let key = (*ports.start(), service.proto.clone());
+                match self.by_port.entry(key) {
+                    Entry::Occupied(_) => return,
+                    Entry::Vacant(e) => {
+                        e.insert(idx);
+                    }
+                }
+            }
+            Some(_) => self.ranges.push(idx),
+            None => {}
+        }
+        for name in std::iter::once(&service.name).chain(&service.aliases) {
+            self.by_name.entry(name.to_lowercase()).or_insert(idx);
+        }
+        self.services.push(service);
+    }
+
+    pub fn get(&self, port: u16, proto: &str) -> Option<&Service> {
+        if let Some(idx) = self.by_port.get(&(port, proto.to_lowercase())) {
+            return self.services.get(*idx);
+        }
+        self.ranges
+            .iter()
+            .filter_map(|idx| self.services.get(*idx))
+            .find(|s| {
+                s.ports.as_ref().is_some_and(|p| p.contains(&port))
+                    && s.proto.eq_ignore_ascii_case(proto)
+            })
+    }
+
+    // case-insensitive lookup by service name or alias, e.g. "www" for http.
+    pub fn by_name(&self, name: &str) -> Option<&Service> {
+        self.by_name
+            .get(&name.to_lowercase())
+            .and_then(|idx| self.services.get(*idx))
+    }
+
+    // the name of a service in reports: the name detected by the sensor, as the registry
+    // names it, or the registry name of the port when nothing was detected.
+    pub fn label(&self, detected: Option<&str>, port: u16, proto: &str) -> Option<String> {
+        match detected {
+            Some(name) => Some(
+                self.by_name(name)
+                    .map_or_else(|| name.to_uppercase(), |s| s.name.to_uppercase()),
+            ),
+            None => self.get(port, proto).map(|s| s.name.to_uppercase()),
         }
-        let s = line.find('#').map_or(line.as_str(), |idx| &line[..idx]);
-        if let Some(x) = re.captures(s) {
-            let Some(service) = x.get(1).map(|v| v.as_str().to_uppercase()) else {
+    }
+
+    // name port/proto [aliases ...] [# comment]
+    pub fn read_etc_services<R: BufRead>(&mut self, rdr: R) -> Result<()> {
+        let re = regex::Regex::new(REGEX_SERVICE)?;
+        for line in rdr.lines().map_while(Result::ok) {
+            if line.starts_with('#') || line.trim().is_empty() {
                 continue;
-            };
-            let Some(port) = x.get(2).map(|v| v.as_str().to_string()) else {
+            }
+            let s = line.find('#').map_or(line.as_str(), |idx| &line[..idx]);
+            if let Some(x) = re.captures(s) {
+                let Some(name) = x.get(1).map(|v| v.as_str().to_string()) else {
+                    continue;
+                };
+                let Some(port) = x.get(2).and_then(|v| v.as_str().parse::<u16>().ok()) else {
+                    continue;
+                };
+                let Some(proto) = x.get(3).map(|v| v.as_str().to_string()) else {
+                    continue;
+                };
+                let aliases = x
+                    .get(4)
+                    .map(|v| v.as_str().split_whitespace().map(String::from).collect())
+                    .unwrap_or_default();
+                self.insert(Service {
+                    name,
+                    ports: Some(port..=port),
+                    proto,
+                    aliases,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // IANA service-names-port-numbers.csv
+    pub fn read_iana_csv<R: Read>(&mut self, rdr: R) -> Result<()> {
+        let mut rdr = csv::ReaderBuilder::new().flexible(true).from_reader(rdr);
+        let headers = rdr.headers()?.clone();
+        let column = |name: &str| {
+            headers
+                .iter()
+                .position(|h| h == name)
+                .ok_or_else(|| anyhow!("no {name} column"))
+        };
+        let name_col = column(IANA_SERVICE_NAME)?;
+        let port_col = column(IANA_PORT_NUMBER)?;
+        let proto_col = column(IANA_TRANSPORT_PROTOCOL)?;
+        for rec in rdr.records().flatten() {
+            let Some(name) = rec.get(name_col).filter(|v| !v.is_empty()) else {
                 continue;
             };
-            let Some(proto) = x.get(3).map(|v| v.as_str().to_string()) else {
+            let Some(proto) = rec.get(proto_col).filter(|v| !v.is_empty()) else {
                 continue;
             };
-            services.insert(format!("{port}/{proto}"), service);
+            // names without a port are kept for the lookup by name
+            let ports = match rec.get(port_col).filter(|v| !v.is_empty()) {
+                Some(v) => match parse_ports(v) {
+                    Some(ports) => Some(ports),
+                    None => continue,
+                },
+                None => None,
+            };
+            self.insert(Service {
+                name: name.to_string(),
+                ports,
+                proto: proto.to_lowercase(),
+                aliases: Vec::new(),
+            });
         }
+        Ok(())
+    }
+}
+
+// "80" or "6000-6063"
+fn parse_ports(s: &str) -> Option<RangeInclusive<u16>> {
+    if let Some((start, end)) = s.split_once('-') {
+        let start = start.trim().parse::<u16>().ok()?;
+        let end = end.trim().parse::<u16>().ok()?;
+        return (start <= end).then_some(start..=end);
+    }
+    let port = s.trim().parse::<u16>().ok()?;
+    Some(port..=port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ServiceRegistry;
+
+    const ETC_SERVICES: &str = "\
+# Network services, Internet style
+ssh             22/tcp                          # SSH Remote Login Protocol
+http            80/tcp          www             # WorldWideWeb HTTP
+domain          53/udp
+kerberos        88/tcp          kerberos5 krb5 kerberos-sec
+sctp-tunneling  9899/sctp
+";
+
+    const IANA: &str = "\
+Service Name,Port Number,Transport Protocol,Description,Assignee,Contact,Registration Date,Modification Date,Reference,Service Code,Unauthorized Use Reported,Assignment Notes
+,0,tcp,Reserved,,,,,,,,
+http,80,tcp,World Wide Web HTTP,,,,,,,,
+x11,6000-6063,tcp,X Window System,,,,,,,,
+dccp-ping,,dccp,,,,,,,,,
+dccp-http-alt,8008,dccp,,,,,,,,,
+";
+
+    #[test]
+    fn etc_services() {
+        let mut registry = ServiceRegistry::new();
+        registry.read_etc_services(ETC_SERVICES.as_bytes()).unwrap();
+        assert_eq!(registry.len(), 5);
+        assert_eq!(
+            registry.get(22, "tcp").map(|s| s.name.as_str()),
+            Some("ssh")
+        );
+        assert!(registry.get(22, "udp").is_none());
+        assert_eq!(
+            registry.get(9899, "sctp").map(|s| s.name.as_str()),
+            Some("sctp-tunneling")
+        );
+        assert_eq!(
+            registry.get(88, "TCP").map(|s| s.aliases.clone()),
+            Some(vec![
+                "kerberos5".to_string(),
+                "krb5".to_string(),
+                "kerberos-sec".to_string()
+            ])
+        );
+        assert_eq!(
+            registry.get(80, "tcp").map(|s| s.aliases.clone()),
+            Some(vec!["www".to_string()])
+        );
+    }
+
+    #[test]
+    fn iana_registry() {
+        let mut registry = ServiceRegistry::new();
+        registry.read_etc_services(ETC_SERVICES.as_bytes()).unwrap();
+        registry.read_iana_csv(IANA.as_bytes()).unwrap();
+        assert_eq!(registry.get(80, "tcp").map(|s| s.aliases.len()), Some(1));
+        assert_eq!(
+            registry.get(6010, "tcp").map(|s| s.name.as_str()),
+            Some("x11")
+        );
+        assert!(registry.get(6064, "tcp").is_none());
+        assert_eq!(
+            registry.get(8008, "dccp").map(|s| s.name.as_str()),
+            Some("dccp-http-alt")
+        );
+        assert_eq!(registry.len(), 8);
+    }
+
+    #[test]
+    fn lookup_by_name() {
+        let mut registry = ServiceRegistry::new();
+        registry.read_etc_services(ETC_SERVICES.as_bytes()).unwrap();
+        registry.read_iana_csv(IANA.as_bytes()).unwrap();
+        let name = |s: &str| registry.by_name(s).map(|s| s.name.as_str());
+        assert_eq!(name("SSH"), Some("ssh"));
+        assert_eq!(name("www"), Some("http"));
+        assert_eq!(name("Krb5"), Some("kerberos"));
+        assert_eq!(name("x11"), Some("x11"));
+        assert_eq!(name("unknown"), None);
+        // dccp-ping has no port
+        assert_eq!(
+            registry.by_name("dccp-ping").map(|s| s.ports.clone()),
+            Some(None)
+        );
+        assert!(registry.get(0, "dccp").is_none());
     }
-    Ok(services)
 }
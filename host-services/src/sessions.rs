@@ -0,0 +1,359 @@
+use crate::hosts::ServerRole;
+use chrono::{DateTime, TimeDelta, Utc};
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BTreeMap, BinaryHeap, HashMap},
+    net::IpAddr,
+};
+
+// durations and byte counts of the sessions to one server port.
+#[derive(Debug, Default)]
+struct Counts {
+    sessions: u64,
+    // no bytes in either direction
+    zero_byte: u64,
+    // bytes in one direction only
+    one_sided: u64,
+    // sessions without a known duration are left out.
+    durations: Histogram,
+}
+
+// significant bits kept of each duration. values are off by less than 1/2^BITS.
+const BITS: u32 = 8;
+
+// log-scale histogram of milliseconds, so a service of many sessions takes bounded memory.
+// values below 2^BITS have their own bucket; larger ones share a bucket with the values of
+// the same top BITS bits. min and max are exact.
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: BTreeMap<u16, u64>,
+    count: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Histogram {
+    fn insert(&mut self, ms: u64) {
+        *self.buckets.entry(bucket(ms)).or_default() += 1;
+        self.min = if self.count == 0 {
+            ms
+        } else {
+            self.min.min(ms)
+        };
+        self.max = self.max.max(ms);
+        self.count += 1;
+    }
+
+    fn merge(&mut self, other: Histogram) {
+        if other.count == 0 {
+            return;
+        }
+        for (idx, n) in other.buckets {
+            *self.buckets.entry(idx).or_default() += n;
+        }
+        self.min = if self.count == 0 {
+            other.min
+        } else {
+            self.min.min(other.min)
+        };
+        self.max = self.max.max(other.max);
+        self.count += other.count;
+    }
+
+    // the value of nearest rank for the percentile `p`, as the middle of its bucket.
+    fn percentile(&self, p: u64) -> u64 {
+        let rank = (self.count * p).div_ceil(100).max(1);
+        let mut seen = 0;
+        for (&idx, &n) in &self.buckets {
+            seen += n;
+            if seen >= rank {
+                let (low, width) = bucket_range(idx);
+                return (low + (width - 1) / 2).clamp(self.min, self.max);
+            }
+        }
+        self.max
+    }
+}
+
+fn bucket(ms: u64) -> u16 {
+    if ms < 1 << BITS {
+        return ms as u16;
+    }
+    let shift = 64 - ms.leading_zeros() - BITS;
+    ((shift << (BITS - 1)) + (ms >> shift) as u32) as u16
+}
+
+// the first value and the number of values of a bucket.
+fn bucket_range(idx: u16) -> (u64, u64) {
+    let idx = u64::from(idx);
+    if idx < 1 << BITS {
+        return (idx, 1);
+    }
+    let shift = (idx >> (BITS - 1)) - 1;
+    let top = idx - (shift << (BITS - 1));
+    (top << shift, 1 << shift)
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LongSession {
+    pub src: IpAddr,
+    pub dest: IpAddr,
+    pub port: u16,
+    pub proto: u8,
+    pub start: Option<DateTime<Utc>>,
+    pub duration: TimeDelta,
+}
+
+// longest first, then by start time and endpoints so that the order does not depend on
+// the order of insertion.
+impl Ord for LongSession {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let key = |s: &Self| (Reverse(s.duration), s.start, s.src, s.dest, s.port, s.proto);
+        key(self).cmp(&key(other))
+    }
+}
+
+impl PartialOrd for LongSession {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// session duration and flow quality per local service.
+#[derive(Debug, Default)]
+pub struct Sessions {
+    services: HashMap<(IpAddr, u16, u8), Counts>,
+    // the first `top` long sessions in their order. the last one is on top of the heap.
+    long: BinaryHeap<LongSession>,
+    long_count: usize,
+    top: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Quality<'a> {
+    pub server: &'a ServerRole,
+    pub sessions: u64,
+    // min, median, p95 and max, if any session has a duration
+    pub durations: Option<[TimeDelta; 4]>,
+    pub zero_byte: f64,
+    pub one_sided: f64,
+}
+
+impl Sessions {
+    // up to `top` long sessions are kept.
+    pub fn new(top: usize) -> Self {
+        Self {
+            top,
+            ..Self::default()
+        }
+    }
+
+    pub fn top(&self) -> usize {
+        self.top
+    }
+
+    // one session to `dest`. sessions of at least `long` are counted, and the longest kept.
+    pub fn insert(
+        &mut self,
+        (src, dest, port, proto): (IpAddr, IpAddr, u16, u8),
+        (sent, received): (u64, u64),
+        (start, duration): (Option<DateTime<Utc>>, Option<TimeDelta>),
+        long: TimeDelta,
+    ) {
+        let counts = self.services.entry((dest, port, proto)).or_default();
+        counts.sessions += 1;
+        match (sent, received) {
+            (0, 0) => counts.zero_byte += 1,
+            (0, _) | (_, 0) => counts.one_sided += 1,
+            _ => {}
+        }
+        let Some(duration) = duration else {
+            return;
+        };
+        counts
+            .durations
+            .insert(u64::try_from(duration.num_milliseconds()).unwrap_or_default());
+        if duration >= long {
+            self.long_count += 1;
+            self.keep_long(LongSession {
+                src,
+                dest,
+                port,
+                proto,
+                start,
+                duration,
+            });
+        }
+    }
+
+    fn keep_long(&mut self, session: LongSession) {
+        if self.long.len() < self.top {
+            self.long.push(session);
+        } else if let Some(mut last) = self.long.peek_mut() {
+            if session < *last {
+                *last = session;
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: Sessions) {
+        for (key, counts) in other.services {
+            let c = self.services.entry(key).or_default();
+            c.sessions += counts.sessions;
+            c.zero_byte += counts.zero_byte;
+            c.one_sided += counts.one_sided;
+            c.durations.merge(counts.durations);
+        }
+        self.long_count += other.long_count;
+        for session in other.long {
+            self.keep_long(session);
+        }
+    }
+
+    // duration distribution and the share of zero-byte and one-sided sessions of each server.
+    pub fn quality<'a>(&self, servers: &'a [ServerRole]) -> Vec<Quality<'a>> {
+        servers
+            .iter()
+            .filter_map(|server| {
+                let host = server.host.parse::<IpAddr>().ok()?;
+                let counts = self
+                    .services
+                    .get(&(host, server.port, server.proto.number))?;
+                let share = |n: u64| n as f64 / counts.sessions as f64;
+                Some(Quality {
+                    server,
+                    sessions: counts.sessions,
+                    zero_byte: share(counts.zero_byte),
+                    one_sided: share(counts.one_sided),
+                    durations: distribution(&counts.durations),
+                })
+            })
+            .collect()
+    }
+
+    // the longest sessions at least as long as the threshold, longest first.
+    pub fn long_sessions(&self) -> Vec<LongSession> {
+        self.long.clone().into_sorted_vec()
+    }
+
+    // the number of sessions at least as long as the threshold, listed or not.
+    pub fn long_count(&self) -> usize {
+        self.long_count
+    }
+}
+
+// min, median, p95 and max, by the nearest-rank method.
+fn distribution(durations: &Histogram) -> Option<[TimeDelta; 4]> {
+    if durations.count == 0 {
+        return None;
+    }
+    let ms = |v: u64| TimeDelta::milliseconds(i64::try_from(v).unwrap_or(i64::MAX));
+    Some([
+        ms(durations.min),
+        ms(durations.percentile(50)),
+        ms(durations.percentile(95)),
+        ms(durations.max),
+    ])
+}
+
+// "0.250" seconds
+pub fn seconds(d: TimeDelta) -> String {
+    format!("{:.3}", d.num_milliseconds() as f64 / 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bucket, bucket_range, seconds, Histogram, Sessions, BITS};
+    use crate::hosts::ServerRole;
+    use crate::protocols::ProtocolTable;
+    use chrono::TimeDelta;
+    use std::net::IpAddr;
+
+    #[test]
+    fn durations_and_shares() {
+        let (client, server): (IpAddr, IpAddr) =
+            ("10.0.0.9".parse().unwrap(), "10.0.0.1".parse().unwrap());
+        let long = TimeDelta::hours(1);
+        let mut sessions = Sessions::new(2);
+        let mut other = Sessions::new(2);
+        for secs in 1..=100 {
+            let bytes = match secs % 10 {
+                0 => (0, 0),
+                1 | 2 => (60, 0),
+                _ => (100, 2000),
+            };
+            let half = if secs % 2 == 0 {
+                &mut sessions
+            } else {
+                &mut other
+            };
+            half.insert(
+                (client, server, 443, 6),
+                bytes,
+                (None, Some(TimeDelta::seconds(secs))),
+                long,
+            );
+        }
+        // three long sessions, of which the two longest are kept
+        for hours in [5, 2, 3] {
+            let half = if hours == 5 {
+                &mut sessions
+            } else {
+                &mut other
+            };
+            half.insert(
+                (client, server, 443, 6),
+                (1, 1),
+                (None, Some(TimeDelta::hours(hours))),
+                long,
+            );
+        }
+        // no duration recorded
+        sessions.insert((client, server, 443, 6), (1, 1), (None, None), long);
+        sessions.merge(other);
+
+        let protocols = ProtocolTable::builtin();
+        let servers = [ServerRole {
+            host: "10.0.0.1".to_string(),
+            port: 443,
+            proto: protocols.protocol(6),
+            confidence: 1.0,
+            reasons: Vec::new(),
+        }];
+        let quality = sessions.quality(&servers);
+        assert_eq!(quality.len(), 1);
+        let q = &quality[0];
+        assert_eq!(q.sessions, 104);
+        // percentiles are off by less than 1/256, min and max are exact
+        let [min, median, p95, max] = q.durations.unwrap();
+        assert_eq!(seconds(min), "1.000");
+        assert_eq!(seconds(median), "52.095");
+        assert_eq!(seconds(p95), "98.047");
+        assert_eq!(max, TimeDelta::hours(5));
+        assert!((q.zero_byte - 10.0 / 104.0).abs() < 1e-9);
+        assert!((q.one_sided - 20.0 / 104.0).abs() < 1e-9);
+
+        assert_eq!(sessions.long_count(), 3);
+        let listed = sessions
+            .long_sessions()
+            .iter()
+            .map(|s| s.duration.num_hours())
+            .collect::<Vec<_>>();
+        assert_eq!(listed, vec![5, 3]);
+    }
+
+    #[test]
+    fn histogram_buckets() {
+        for ms in [0, 255, 256, 257, 1000, 51_000, 3_600_000, u64::MAX] {
+            let (low, width) = bucket_range(bucket(ms));
+            assert!(low <= ms && ms - low < width, "{ms}");
+            assert!(width == 1 || width <= low >> (BITS - 1), "{ms}");
+        }
+        let mut h = Histogram::default();
+        for ms in 1..=1000 {
+            h.insert(ms);
+        }
+        assert_eq!(h.percentile(50), 500);
+        assert!(h.percentile(95).abs_diff(950) <= 2);
+    }
+}
@@ -0,0 +1,75 @@
+use crate::hosts::Hosts;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeSet, path::Path, time::SystemTime};
+
+// host counters kept between runs so that new log files are merged into them.
+// only the host counters are kept. the other reports cover the files of the current run.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct State {
+    // files already merged. they are skipped when given again.
+    pub files: BTreeSet<FileId>,
+    pub hosts: Hosts,
+}
+
+// a file by its canonical path, length and modification time, so that `./a.log` and
+// `a.log` are the same file and a file rotated into the same path is a new one.
+#[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct FileId {
+    path: String,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl FileId {
+    pub fn of(path: &str) -> Result<Self> {
+        let canonical =
+            std::fs::canonicalize(path).map_err(|e| anyhow!("fail to open {path}. {e}"))?;
+        let metadata = std::fs::metadata(&canonical)?;
+        Ok(Self {
+            path: canonical.to_string_lossy().into_owned(),
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+}
+
+impl State {
+    // an empty state if the file does not exist yet.
+    pub fn load(path: &str) -> Result<Self> {
+        if !Path::new(path).exists() {
+            return Ok(Self::default());
+        }
+        let s = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&s)?)
+    }
+
+    // written to a temporary file first so that a failed run keeps the previous state.
+    pub fn save(&self, path: &str) -> Result<()> {
+        let tmp = format!("{path}.tmp");
+        std::fs::write(&tmp, serde_json::to_string(self)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FileId;
+
+    #[test]
+    fn file_id() {
+        let dir = std::env::temp_dir().join(format!("state-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("conn.log");
+        std::fs::write(&path, "a\n").unwrap();
+        let id = FileId::of(path.to_str().unwrap()).unwrap();
+        let dotted = dir.join(".").join("conn.log");
+        assert_eq!(FileId::of(dotted.to_str().unwrap()).unwrap(), id);
+
+        // rotated: another file at the same path
+        std::fs::write(&path, "b\nc\n").unwrap();
+        assert_ne!(FileId::of(path.to_str().unwrap()).unwrap(), id);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,348 @@
+use crate::{protocols::ProtocolTable, services::ServiceRegistry};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+};
+
+// direction of a flow relative to the local networks.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Inbound,
+    Outbound,
+    Internal,
+}
+
+impl Direction {
+    pub fn of(src_is_local: bool, dest_is_local: bool) -> Option<Self> {
+        match (src_is_local, dest_is_local) {
+            (false, true) => Some(Direction::Inbound),
+            (true, false) => Some(Direction::Outbound),
+            (true, true) => Some(Direction::Internal),
+            (false, false) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Direction::Inbound => write!(f, "inbound"),
+            Direction::Outbound => write!(f, "outbound"),
+            Direction::Internal => write!(f, "internal"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct Volume {
+    pub sessions: u64,
+    pub bytes: u64,
+    pub packets: u64,
+}
+
+impl Volume {
+    // volume of a single session.
+    pub fn session(bytes: u64, packets: u64) -> Self {
+        Self {
+            sessions: 1,
+            bytes,
+            packets,
+        }
+    }
+
+    fn add(&mut self, other: Volume) {
+        // counters of broken records can be near u64::MAX
+        self.sessions = self.sessions.saturating_add(other.sessions);
+        self.bytes = self.bytes.saturating_add(other.bytes);
+        self.packets = self.packets.saturating_add(other.packets);
+    }
+}
+
+// (server host, port, protocol number, direction)
+type ServiceKey = (IpAddr, u16, u8, Direction);
+
+// traffic totals per local host and per local service.
+// keys are not allocated per flow; hosts and protocols are named when reporting.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    hosts: HashMap<(IpAddr, Direction), Volume>,
+    // volume and the service name detected by the sensor.
+    services: HashMap<ServiceKey, (Volume, Option<String>)>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HostEntry {
+    host: String,
+    #[serde(flatten)]
+    volume: Volume,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ServiceEntry {
+    host: String,
+    port: u16,
+    proto: String,
+    service: String,
+    #[serde(flatten)]
+    volume: Volume,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct TopTalkers<T> {
+    bytes: Vec<T>,
+    packets: Vec<T>,
+    sessions: Vec<T>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct TrafficReport {
+    hosts: BTreeMap<Direction, TopTalkers<HostEntry>>,
+    services: BTreeMap<Direction, TopTalkers<ServiceEntry>>,
+}
+
+impl Traffic {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert_host(&mut self, host: IpAddr, direction: Direction, volume: Volume) {
+        self.hosts.entry((host, direction)).or_default().add(volume);
+    }
+
+    // `name` is the service name detected by the sensor, if any.
+    pub fn insert_service(
+        &mut self,
+        (host, port, proto): (IpAddr, u16, u8),
+        name: Option<&str>,
+        direction: Direction,
+        volume: Volume,
+    ) {
+        let entry = self
+            .services
+            .entry((host, port, proto, direction))
+            .or_default();
+        entry.0.add(volume);
+        if entry.1.is_none() {
+            entry.1 = name.map(ToString::to_string);
+        }
+    }
+
+    pub fn merge(&mut self, other: Traffic) {
+        for (key, volume) in other.hosts {
+            self.hosts.entry(key).or_default().add(volume);
+        }
+        for (key, (volume, name)) in other.services {
+            let entry = self.services.entry(key).or_default();
+            entry.0.add(volume);
+            if entry.1.is_none() {
+                entry.1 = name;
+            }
+        }
+    }
+
+    pub fn report(
+        &self,
+        top: usize,
+        services: &ServiceRegistry,
+        protocols: &ProtocolTable,
+    ) -> TrafficReport {
+        let mut report = TrafficReport::default();
+
+        let mut sorted = self.hosts.iter().collect::<Vec<_>>();
+        sorted.sort_by_key(|(key, _)| *key);
+        let mut hosts: BTreeMap<Direction, Vec<HostEntry>> = BTreeMap::new();
+        for ((host, direction), volume) in sorted {
+            hosts.entry(*direction).or_default().push(HostEntry {
+                host: host.to_string(),
+                volume: *volume,
+            });
+        }
+        for (direction, entries) in hosts {
+            report
+                .hosts
+                .insert(direction, top_talkers(&entries, top, |e| &e.volume));
+        }
+
+        let mut sorted = self.services.iter().collect::<Vec<_>>();
+        sorted.sort_by_key(|(key, _)| *key);
+        let mut servers: BTreeMap<Direction, Vec<ServiceEntry>> = BTreeMap::new();
+        for ((host, port, proto, direction), (volume, name)) in sorted {
+            let proto = protocols.protocol(*proto);
+            let service = services
+                .label(name.as_deref(), *port, &proto.name)
+                .unwrap_or_else(|| format!("{port}/{proto}"));
+            servers.entry(*direction).or_default().push(ServiceEntry {
+                host: host.to_string(),
+                port: *port,
+                proto: proto.name,
+                service,
+                volume: *volume,
+            });
+        }
+        for (direction, entries) in servers {
+            report
+                .services
+                .insert(direction, top_talkers(&entries, top, |e| &e.volume));
+        }
+
+        report
+    }
+}
+
+fn top_talkers<T, F>(entries: &[T], top: usize, volume: F) -> TopTalkers<T>
+where
+    T: Clone,
+    F: Fn(&T) -> &Volume,
+{
+    let by = |metric: fn(&Volume) -> u64| {
+        let mut v = entries.to_vec();
+        v.sort_by_key(|e| std::cmp::Reverse(metric(volume(e))));
+        v.truncate(top);
+        v
+    };
+    TopTalkers {
+        bytes: by(|v| v.bytes),
+        packets: by(|v| v.packets),
+        sessions: by(|v| v.sessions),
+    }
+}
+
+impl TrafficReport {
+    pub fn print(&self) {
+        for (direction, talkers) in &self.hosts {
+            for (metric, entries) in talkers.metrics() {
+                println!("\n{direction} hosts by {metric}");
+                println!("host\tsessions\tbytes\tpackets");
+                for e in entries {
+                    println!(
+                        "{}\t{}\t{}\t{}",
+                        e.host, e.volume.sessions, e.volume.bytes, e.volume.packets
+                    );
+                }
+            }
+        }
+        for (direction, talkers) in &self.services {
+            for (metric, entries) in talkers.metrics() {
+                println!("\n{direction} services by {metric}");
+                println!("host\tport\tproto\tservice\tsessions\tbytes\tpackets");
+                for e in entries {
+                    println!(
+                        "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                        e.host,
+                        e.port,
+                        e.proto,
+                        e.service,
+                        e.volume.sessions,
+                        e.volume.bytes,
+                        e.volume.packets
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl<T> TopTalkers<T> {
+    fn metrics(&self) -> [(&str, &Vec<T>); 3] {
+        [
+            ("bytes", &self.bytes),
+            ("packets", &self.packets),
+            ("sessions", &self.sessions),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Direction, Traffic, Volume};
+    use crate::{protocols::ProtocolTable, services::ServiceRegistry};
+    use std::net::IpAddr;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn top_talkers_by_metric() {
+        let mut traffic = Traffic::new();
+        let protocols = ProtocolTable::builtin();
+        traffic.insert_host(
+            ip("10.0.0.1"),
+            Direction::Outbound,
+            Volume::session(1000, 2),
+        );
+        for _ in 0..3 {
+            traffic.insert_host(ip("10.0.0.2"), Direction::Outbound, Volume::session(10, 5));
+        }
+        traffic.insert_host(ip("10.0.0.3"), Direction::Inbound, Volume::session(1, 1));
+        traffic.insert_service(
+            (ip("10.0.0.3"), 8443, 6),
+            Some("https"),
+            Direction::Inbound,
+            Volume::session(1, 1),
+        );
+
+        let report = traffic.report(1, &ServiceRegistry::new(), &protocols);
+        let outbound = &report.hosts[&Direction::Outbound];
+        assert_eq!(outbound.bytes[0].host, "10.0.0.1");
+        assert_eq!(outbound.packets[0].host, "10.0.0.2");
+        assert_eq!(outbound.sessions[0].volume.sessions, 3);
+        assert_eq!(outbound.bytes.len(), 1);
+        assert_eq!(
+            report.services[&Direction::Inbound].bytes[0].service,
+            "HTTPS"
+        );
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["hosts"]["inbound"]["bytes"][0]["host"], "10.0.0.3");
+        assert_eq!(json["services"]["inbound"]["sessions"][0]["port"], 8443);
+
+        let mut registry = ServiceRegistry::new();
+        registry
+            .read_etc_services("http 80/tcp www\n".as_bytes())
+            .unwrap();
+        traffic.insert_service(
+            (ip("10.0.0.3"), 80, 6),
+            Some("www"),
+            Direction::Inbound,
+            Volume::session(1000, 1),
+        );
+        let report = traffic.report(1, &registry, &protocols);
+        assert_eq!(
+            report.services[&Direction::Inbound].bytes[0].service,
+            "HTTP"
+        );
+    }
+
+    #[test]
+    fn saturating_volume() {
+        let mut traffic = Traffic::new();
+        let protocols = ProtocolTable::builtin();
+        traffic.insert_host(
+            ip("10.0.0.1"),
+            Direction::Outbound,
+            Volume::session(u64::MAX, 1),
+        );
+        traffic.insert_host(
+            ip("10.0.0.1"),
+            Direction::Outbound,
+            Volume::session(10, u64::MAX),
+        );
+        let report = traffic.report(1, &ServiceRegistry::new(), &protocols);
+        let top = &report.hosts[&Direction::Outbound].bytes[0].volume;
+        assert_eq!(
+            (top.sessions, top.bytes, top.packets),
+            (2, u64::MAX, u64::MAX)
+        );
+    }
+
+    #[test]
+    fn direction_of_flow() {
+        assert_eq!(Direction::of(false, true), Some(Direction::Inbound));
+        assert_eq!(Direction::of(true, false), Some(Direction::Outbound));
+        assert_eq!(Direction::of(true, true), Some(Direction::Internal));
+        assert_eq!(Direction::of(false, false), None);
+    }
+}
@@ -0,0 +1,318 @@
+use crate::{
+    net::{self, IpMapper, NetGroup},
+    netdb,
+};
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::{collections::HashSet, net::IpAddr, str::FromStr};
+use toml::Spanned;
+
+// zone name for addresses that match no zone in the policy.
+pub const DEFAULT_ZONE: &str = "external";
+
+// zone policy file
+//
+// local = ["internal", "dmz"]
+// exact_ranges = true
+// database = "zones.ngdb"
+//
+// [[zone]]
+// name = "internal"
+// networks = ["10.0.0.0/8", "192.168.0.0/16"]
+#[derive(Debug, Deserialize)]
+pub struct ZonePolicy {
+    // zones treated as the local network. every zone if not given.
+    local: Option<Vec<String>>,
+    // store address ranges as the exact networks covering them.
+    #[serde(default)]
+    exact_ranges: bool,
+    // a network group database compiled by test-rangeinclusive. the label of each
+    // network is its zone.
+    database: Option<String>,
+    #[serde(rename = "zone", default)]
+    zones: Vec<Zone>,
+    // the networks of `database` with their zones
+    #[serde(skip)]
+    compiled: Vec<(String, NetGroup)>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Zone {
+    name: String,
+    networks: Vec<String>,
+}
+
+// the networks of the zones with their positions in the file, to report invalid ones.
+#[derive(Deserialize)]
+struct SpannedZones {
+    #[serde(rename = "zone", default)]
+    zones: Vec<SpannedZone>,
+}
+
+#[derive(Deserialize)]
+struct SpannedZone {
+    name: String,
+    networks: Vec<Spanned<String>>,
+}
+
+impl ZonePolicy {
+    pub fn from_path(path: &str) -> Result<Self> {
+        let s = std::fs::read_to_string(path)?;
+        Self::parse(&s)
+    }
+
+    // a `local` name matching no zone and an invalid network are errors. otherwise a typo
+    // would shrink a zone or the local network and count its flows as external.
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut policy: Self = toml::from_str(s)?;
+        if let Some(path) = &policy.database {
+            policy.compiled = netdb::load(path)?;
+        }
+        for name in policy.local.iter().flatten() {
+            if !policy.zones.iter().any(|z| &z.name == name)
+                && !policy.compiled.iter().any(|(zone, _)| zone == name)
+            {
+                bail!("unknown zone \"{name}\" in local");
+            }
+        }
+        let spanned: SpannedZones = toml::from_str(s)?;
+        for zone in &spanned.zones {
+            for network in &zone.networks {
+                if NetGroup::from_str(network.get_ref().trim()).is_err() {
+                    let line = s[..network.span().start].lines().count().max(1);
+                    bail!(
+                        "invalid network \"{}\" in zone \"{}\" at line {line}",
+                        network.get_ref(),
+                        zone.name
+                    );
+                }
+            }
+        }
+        Ok(policy)
+    }
+
+    // policy with one "internal" zone made of the given networks.
+    pub fn with_local_networks(networks: &[&str]) -> Self {
+        Self {
+            local: None,
+            exact_ranges: false,
+            database: None,
+            zones: vec![Zone {
+                name: "internal".to_string(),
+                networks: networks.iter().map(ToString::to_string).collect(),
+            }],
+            compiled: Vec::new(),
+        }
+    }
+
+    fn networks(&self, zone: &Zone) -> Vec<String> {
+        if self.exact_ranges {
+            net::exact_networks(&zone.networks)
+        } else {
+            zone.networks.clone()
+        }
+    }
+
+    pub fn zones(&self) -> Option<IpMapper> {
+        IpMapper::from_entries(self.entries(|_| true))
+    }
+
+    pub fn local_networks(&self) -> Option<IpMapper> {
+        IpMapper::from_entries(self.entries(|zone| {
+            self.local
+                .as_ref()
+                .is_none_or(|l| l.iter().any(|n| n == zone))
+        }))
+    }
+
+    // the networks of the zones accepted by `filter`, from the file and the database.
+    fn entries<F: Fn(&str) -> bool>(&self, filter: F) -> Vec<(String, NetGroup)> {
+        let groups = self
+            .zones
+            .iter()
+            .filter(|z| filter(&z.name))
+            .map(|z| (z.name.clone(), self.networks(z)))
+            .collect::<Vec<_>>();
+        let mut entries = net::labeled_netgroups(&groups);
+        entries.extend(
+            self.compiled
+                .iter()
+                .filter(|(zone, _)| filter(zone))
+                .cloned(),
+        );
+        entries
+    }
+}
+
+#[derive(Debug, Default)]
+struct ZoneFlow {
+    sessions: u64,
+    bytes: u64,
+    sources: HashSet<IpAddr>,
+    destinations: HashSet<IpAddr>,
+}
+
+impl ZoneFlow {
+    fn merge(&mut self, other: ZoneFlow) {
+        self.sessions += other.sessions;
+        self.bytes = self.bytes.saturating_add(other.bytes);
+        self.sources.extend(other.sources);
+        self.destinations.extend(other.destinations);
+    }
+}
+
+// sessions, bytes and distinct hosts per (source zone, destination zone).
+#[derive(Debug, Default)]
+pub struct ZoneMatrix {
+    // zones are few, so a list is searched instead of allocating a key per flow.
+    flows: Vec<((String, String), ZoneFlow)>,
+}
+
+impl ZoneMatrix {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, zones: &IpMapper, src: IpAddr, dest: IpAddr, bytes: u64) {
+        let flow = self.flow_mut(zone_of(zones, src), zone_of(zones, dest));
+        flow.sessions += 1;
+        flow.bytes = flow.bytes.saturating_add(bytes);
+        flow.sources.insert(src);
+        flow.destinations.insert(dest);
+    }
+
+    pub fn merge(&mut self, other: ZoneMatrix) {
+        for ((src, dest), flow) in other.flows {
+            self.flow_mut(&src, &dest).merge(flow);
+        }
+    }
+
+    fn flow_mut(&mut self, src: &str, dest: &str) -> &mut ZoneFlow {
+        let idx = match self
+            .flows
+            .iter()
+            .position(|((s, d), _)| s == src && d == dest)
+        {
+            Some(idx) => idx,
+            None => {
+                let key = (src.to_string(), dest.to_string());
+                self.flows.push((key, ZoneFlow::default()));
+                self.flows.len() - 1
+            }
+        };
+        &mut self.flows[idx].1
+    }
+
+    pub fn print(&self) {
+        let mut flows = self.flows.iter().collect::<Vec<_>>();
+        flows.sort_by(|a, b| a.0.cmp(&b.0));
+        println!("\nzone matrix {} entries", flows.len());
+        println!("source\tdestination\tsessions\tbytes\tsources\tdestinations");
+        for ((src, dest), flow) in flows {
+            println!(
+                "{src}\t{dest}\t{}\t{}\t{}\t{}",
+                flow.sessions,
+                flow.bytes,
+                flow.sources.len(),
+                flow.destinations.len()
+            );
+        }
+    }
+}
+
+fn zone_of(zones: &IpMapper, ipaddr: IpAddr) -> &str {
+    zones
+        .lookup(ipaddr)
+        .map_or(DEFAULT_ZONE, |(label, _)| label)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ZoneMatrix, ZonePolicy};
+    use std::net::IpAddr;
+
+    const POLICY: &str = r#"
+    local = ["internal", "management"]
+
+    [[zone]]
+    name = "internal"
+    networks = ["10.0.0.0/8"]
+
+    [[zone]]
+    name = "management"
+    networks = ["10.0.100.0/24", "fd00:100::/64"]
+
+    [[zone]]
+    name = "dmz"
+    networks = ["203.0.113.0/24"]
+    "#;
+
+    #[test]
+    fn policy_local_networks() {
+        let policy = ZonePolicy::parse(POLICY).unwrap();
+        let local_net = policy.local_networks().unwrap();
+        assert!(local_net.contains("10.0.100.1"));
+        assert!(local_net.contains("fd00:100::1"));
+        assert!(!local_net.contains("203.0.113.10"));
+    }
+
+    #[test]
+    fn unknown_local_zone() {
+        let policy = POLICY.replace("\"management\"]", "\"managment\"]");
+        let err = ZonePolicy::parse(&policy).unwrap_err();
+        assert_eq!(err.to_string(), "unknown zone \"managment\" in local");
+    }
+
+    #[test]
+    fn invalid_zone_network() {
+        let policy = POLICY.replace("fd00:100::/64", "fd00:100::/644");
+        let err = ZonePolicy::parse(&policy).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid network \"fd00:100::/644\" in zone \"management\" at line 10"
+        );
+    }
+
+    #[test]
+    fn compiled_zones() {
+        let policy = format!("database = \"sample_zones.ngdb\"\n{POLICY}")
+            .replace("\"management\"]", "\"management\", \"voip\"]");
+        let policy = ZonePolicy::parse(&policy).unwrap();
+        let zones = policy.zones().unwrap();
+        let zone = |s: &str| zones.lookup(s.parse().unwrap()).map(|(zone, _)| zone);
+        assert_eq!(zone("10.0.100.200"), Some("voip"));
+        assert_eq!(zone("10.0.100.20"), Some("management"));
+        assert_eq!(zone("198.51.100.20"), Some("partners"));
+        assert_eq!(zone("2001:db8:100::1"), Some("partners"));
+        let local_net = policy.local_networks().unwrap();
+        assert!(local_net.contains("10.0.100.200"));
+        assert!(!local_net.contains("198.51.100.20"));
+
+        let missing = POLICY.replace("local =", "database = \"missing.ngdb\"\nlocal =");
+        let err = ZonePolicy::parse(&missing).unwrap_err();
+        assert!(err.to_string().starts_with("fail to read missing.ngdb."));
+    }
+
+    #[test]
+    fn zone_to_zone_flows() {
+        let policy = ZonePolicy::parse(POLICY).unwrap();
+        let zones = policy.zones().unwrap();
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let mut matrix = ZoneMatrix::new();
+        matrix.insert(&zones, ip("10.0.100.5"), ip("10.1.1.1"), 100);
+        matrix.insert(&zones, ip("10.1.1.1"), ip("203.0.113.10"), 10);
+        matrix.insert(&zones, ip("203.0.113.10"), ip("8.8.8.8"), 1);
+        let mut other = ZoneMatrix::new();
+        other.insert(&zones, ip("10.0.100.6"), ip("10.1.1.1"), 50);
+        matrix.merge(other);
+
+        let flow = matrix.flow_mut("management", "internal");
+        assert_eq!(flow.sessions, 2);
+        assert_eq!(flow.bytes, 150);
+        assert_eq!(flow.sources.len(), 2);
+        assert_eq!(flow.destinations.len(), 1);
+        assert_eq!(matrix.flow_mut("internal", "dmz").sessions, 1);
+        assert_eq!(matrix.flow_mut("dmz", "external").sessions, 1);
+        assert_eq!(matrix.flows.len(), 3);
+    }
+}
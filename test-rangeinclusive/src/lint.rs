@@ -0,0 +1,211 @@
+use crate::{
+    netgroup::{self, NetGroup, NetGroupError},
+    netset::NetSet,
+};
+use std::net::IpAddr;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Problem {
+    // a line that does not parse, including reversed ranges and mixed address families
+    Invalid(NetGroupError),
+    // the same addresses as an earlier line, however written
+    Duplicate(usize),
+    // every address is also in the entry of the line
+    CoveredBy(usize),
+    // some addresses are also in the entry of the line
+    Overlaps(usize),
+}
+
+// a problem of one line. line numbers start at 1.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Finding {
+    pub line: usize,
+    pub text: String,
+    pub problem: Problem,
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.problem {
+            Problem::Invalid(
+                e @ (NetGroupError::ReversedRange(..) | NetGroupError::MixedFamilies(..)),
+            ) => write!(f, "{e}"),
+            Problem::Invalid(e) => write!(f, "invalid syntax. {e}"),
+            Problem::Duplicate(line) => write!(f, "duplicate of line {line}"),
+            Problem::CoveredBy(line) => write!(f, "covered by line {line}"),
+            Problem::Overlaps(line) => write!(f, "partially overlaps line {line}"),
+        }?;
+        write!(f, " ({})", self.text)
+    }
+}
+
+// check every line of a network group list. findings are in line order.
+// the valid entries are returned too, to write a cleaned list from them.
+pub fn lint(source: &str) -> (Vec<Finding>, Vec<NetGroup>) {
+    let mut findings = Vec::new();
+    // (first address, last address, line, text)
+    let mut entries = Vec::new();
+    let mut netgroups = Vec::new();
+    for (idx, text) in source.lines().enumerate() {
+        match netgroup::parse_line(text) {
+            Ok(Some((net, _))) => {
+                let (first, last) = net.bounds();
+                entries.push((first, last, idx + 1, text.trim()));
+                netgroups.push(net);
+            }
+            Ok(None) => {}
+            Err(e) => findings.push(Finding {
+                line: idx + 1,
+                text: text.trim().to_string(),
+                problem: Problem::Invalid(e),
+            }),
+        }
+    }
+
+    // sorted by first address and widest first, so an entry can only be covered by or
+    // overlap the one reaching furthest among those before it, or duplicate the one just
+    // before it.
+    entries.sort_unstable_by_key(|&(first, last, line, _)| (first, std::cmp::Reverse(last), line));
+    let mut furthest: Option<(IpAddr, IpAddr, usize)> = None;
+    let mut previous: Option<(IpAddr, IpAddr, usize)> = None;
+    for &(first, last, line, text) in &entries {
+        let problem = match (previous, furthest) {
+            (Some((f, l, other)), _) if (f, l) == (first, last) => Some(Problem::Duplicate(other)),
+            (_, Some((f, l, other))) if f.is_ipv4() == first.is_ipv4() => {
+                if l >= last {
+                    Some(Problem::CoveredBy(other))
+                } else if l >= first {
+                    Some(Problem::Overlaps(other))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        if let Some(problem) = problem {
+            findings.push(Finding {
+                line,
+                text: text.to_string(),
+                problem,
+            });
+        }
+        // a duplicate refers to the first line of its kind
+        if previous.is_none_or(|(f, l, _)| (f, l) != (first, last)) {
+            previous = Some((first, last, line));
+        }
+        if furthest.is_none_or(|(f, l, _)| f.is_ipv4() != first.is_ipv4() || last > l) {
+            furthest = Some((first, last, line));
+        }
+    }
+    findings.sort_by_key(|f| f.line);
+    (findings, netgroups)
+}
+
+// the valid entries, merged and written one network group per line.
+pub fn cleaned(netgroups: Vec<NetGroup>) -> anyhow::Result<String> {
+    let set = netgroups.into_iter().collect::<NetSet>();
+    let mut out = String::new();
+    for net in set.netgroups()? {
+        out.push_str(&net.to_string());
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cleaned, lint, Problem};
+    use crate::netgroup::NetGroupError;
+
+    const LIST: &str = "# blocklist
+10.0.0.0/8
+10.1.0.0/16
+10.0.0.0/255.0.0.0
+192.168.1.10..=192.168.1.20
+192.168.1.15-192.168.1.30   # overlaps the range above
+10.0.0.300
+192.168.2.9-1
+10.0.0.1-fd00::1
+fd00::/8
+fd00::1 lab
+
+172.16.0.0-172.16.255.255
+172.16.0.0/16
+";
+
+    #[test]
+    fn findings() {
+        let (findings, netgroups) = lint(LIST);
+        let problems = findings
+            .iter()
+            .map(|f| (f.line, f.problem.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            problems,
+            vec![
+                (3, Problem::CoveredBy(2)),
+                (4, Problem::Duplicate(2)),
+                (6, Problem::Overlaps(5)),
+                (
+                    7,
+                    Problem::Invalid(NetGroupError::InvalidAddress("10.0.0.300".to_string()))
+                ),
+                (
+                    8,
+                    Problem::Invalid(NetGroupError::ReversedRange(
+                        "192.168.2.9".parse().unwrap(),
+                        "192.168.2.1".parse().unwrap()
+                    ))
+                ),
+                (
+                    9,
+                    Problem::Invalid(NetGroupError::MixedFamilies(
+                        "10.0.0.1".parse().unwrap(),
+                        "fd00::1".parse().unwrap()
+                    ))
+                ),
+                (11, Problem::CoveredBy(10)),
+                (14, Problem::Duplicate(13)),
+            ]
+        );
+        assert_eq!(
+            findings[0].to_string(),
+            "line 3: covered by line 2 (10.1.0.0/16)"
+        );
+        assert_eq!(
+            findings[4].to_string(),
+            "line 8: reversed range 192.168.2.9..=192.168.2.1 (192.168.2.9-1)"
+        );
+        assert_eq!(netgroups.len(), 9);
+
+        assert_eq!(
+            cleaned(netgroups).unwrap(),
+            "10.0.0.0/8\n172.16.0.0/16\n192.168.1.10..=192.168.1.30\nfd00::/8\n"
+        );
+    }
+
+    #[test]
+    fn duplicates_inside_wider_entry() {
+        let (findings, _) = lint("10.0.0.0/8\n10.1.0.0/16\n10.1.0.0-10.1.255.255\n10.1.0.0/16\n");
+        let problems = findings
+            .iter()
+            .map(|f| (f.line, f.problem.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            problems,
+            vec![
+                (2, Problem::CoveredBy(1)),
+                (3, Problem::Duplicate(2)),
+                (4, Problem::Duplicate(2)),
+            ]
+        );
+        assert_eq!(findings[1].text, "10.1.0.0-10.1.255.255");
+    }
+
+    #[test]
+    fn clean_list() {
+        let (findings, _) = lint("10.0.0.0/24\n10.0.1.0/24\n\n# comment\n::1\n");
+        assert!(findings.is_empty());
+    }
+}
@@ -1,17 +1,261 @@
+mod lint;
 mod mapper;
+mod netdb;
 mod netgroup;
+mod netset;
+mod rangedb;
+mod reload;
 
-use crate::mapper::SearchTree;
+use crate::{mapper::SearchTree, netdb::NetDb, netgroup::NetGroup, netset::NetSet};
+use anyhow::{anyhow, bail, Result};
 use ipnet::{IpAddrRange, IpNet, IpSub, Ipv4AddrRange, Ipv4Net, Ipv6Net};
 use std::{
     net::{IpAddr, Ipv4Addr},
     str::FromStr,
 };
-use tracing::error;
+use tracing::{error, info, warn};
+
+const USAGE: &str = "usage: test-rangeinclusive [command]
+    compile [--exact] <list> <db>
+    lookup <db> [--source <list>] <ip>...
+    search <list> [--all] <ip>...
+    watch <list> [--interval <seconds>]
+    rangedb <csv> <ip>...
+    cidrs <network group>...
+    set union|intersection|difference|aggregate [--ranges] <list>...
+    lint <list> [--write <cleaned list>]";
 
 fn main() {
     tracing_subscriber::fmt::init();
 
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let ret = match args.first().map(String::as_str) {
+        None => {
+            demo();
+            Ok(())
+        }
+        Some("compile") => compile_db(&args[1..]),
+        Some("lookup") => lookup_db(&args[1..]),
+        Some("search") => search_list(&args[1..]),
+        Some("watch") => watch_list(&args[1..]),
+        Some("rangedb") => lookup_rangedb(&args[1..]),
+        Some("cidrs") => print_cidrs(&args[1..]),
+        Some("set") => set_operation(&args[1..]),
+        Some("lint") => lint_list(&args[1..]),
+        Some(_) => Err(anyhow!(USAGE)),
+    };
+    if let Err(e) = ret {
+        error!("{e}");
+        std::process::exit(1);
+    }
+}
+
+// compile a network group list into a binary database.
+// `--exact` stores ranges as the networks covering them.
+fn compile_db(args: &[String]) -> Result<()> {
+    let (exact, args) = match args.split_first() {
+        Some((flag, rest)) if flag == "--exact" => (true, rest),
+        _ => (false, args),
+    };
+    let [list, db] = args else {
+        bail!(USAGE);
+    };
+    let source = std::fs::read_to_string(list).map_err(|e| anyhow!("fail to read {list}. {e}"))?;
+    let bytes = netdb::compile(&source, exact)?;
+    // every record is read back before writing, so a database that does not load is
+    // never left behind
+    let compiled = NetDb::from_bytes(bytes.clone())?;
+    compiled.entries()?;
+    std::fs::write(db, &bytes).map_err(|e| anyhow!("fail to write {db}. {e}"))?;
+    println!("compiled {} network groups from {list}", compiled.len());
+    Ok(())
+}
+
+// load a compiled database and search the addresses in it.
+// with `--source`, a database compiled from another version of the list is refused.
+fn lookup_db(args: &[String]) -> Result<()> {
+    let (path, mut rest) = args.split_first().ok_or_else(|| anyhow!(USAGE))?;
+    let db = NetDb::open(path)?;
+    if let [flag, list, ips @ ..] = rest {
+        if flag == "--source" {
+            let source =
+                std::fs::read_to_string(list).map_err(|e| anyhow!("fail to read {list}. {e}"))?;
+            if db.is_stale(&source) {
+                bail!("{path} is stale. compile it again from {list}");
+            }
+            rest = ips;
+        }
+    }
+    for ip in rest {
+        let ip = IpAddr::from_str(ip).map_err(|e| anyhow!("invalid ip address {ip}. {e}"))?;
+        println!("{ip}\t{}", db.contains(ip));
+    }
+    Ok(())
+}
+
+// print the most specific network group of a list containing each address and its label,
+// or every one of them with `--all`.
+fn search_list(args: &[String]) -> Result<()> {
+    let (list, mut ips) = args.split_first().ok_or_else(|| anyhow!(USAGE))?;
+    let all = ips.first().is_some_and(|a| a == "--all");
+    if all {
+        ips = &ips[1..];
+    }
+    let source = std::fs::read_to_string(list).map_err(|e| anyhow!("fail to read {list}. {e}"))?;
+    let patterns = source.lines().collect::<Vec<_>>();
+    let tree = SearchTree::build_labeled(&patterns)?;
+    for ip in ips {
+        let ip = IpAddr::from_str(ip).map_err(|e| anyhow!("invalid ip address {ip}. {e}"))?;
+        let found = match &tree {
+            Some(t) if all => t.matches(ip),
+            Some(t) => t.most_specific(ip).into_iter().collect(),
+            None => Vec::new(),
+        };
+        if found.is_empty() {
+            println!("{ip}\t-");
+        }
+        for m in found {
+            println!(
+                "{ip}\t{}\t{}",
+                m.group,
+                m.value.as_deref().unwrap_or_default()
+            );
+        }
+    }
+    Ok(())
+}
+
+// search the addresses read from stdin, one per line, while the list is reloaded
+// whenever it changes.
+fn watch_list(args: &[String]) -> Result<()> {
+    let (list, interval) = match args {
+        [list] => (list, 1),
+        [list, flag, secs] if flag == "--interval" => (
+            list,
+            secs.parse::<u64>()
+                .map_err(|e| anyhow!("invalid interval {secs}. {e}"))?,
+        ),
+        _ => bail!(USAGE),
+    };
+    let live = std::sync::Arc::new(reload::LiveTree::open(list)?);
+    let _watcher = live.watch(std::time::Duration::from_secs(interval));
+    for line in std::io::stdin().lines().map_while(Result::ok) {
+        let ip = line.trim();
+        let Ok(ip) = IpAddr::from_str(ip) else {
+            warn!("invalid ip address {ip}");
+            continue;
+        };
+        let snapshot = live.snapshot();
+        match snapshot.tree().and_then(|t| t.most_specific(ip)) {
+            Some(m) => println!(
+                "{ip}\t{}\t{}",
+                m.group,
+                m.value.as_deref().unwrap_or_default()
+            ),
+            None => println!("{ip}\t-"),
+        }
+    }
+    Ok(())
+}
+
+// load a range database in csv and print the attributes of the range containing each
+// address, under the column names of its header.
+fn lookup_rangedb(args: &[String]) -> Result<()> {
+    let (path, ips) = args.split_first().ok_or_else(|| anyhow!(USAGE))?;
+    let db = rangedb::RangeDb::open(path)?;
+    info!("loaded {} ranges from {path}", db.len());
+    if !db.columns().is_empty() {
+        println!("ip\tnetwork group\t{}", db.columns().join("\t"));
+    }
+    for ip in ips {
+        let ip = IpAddr::from_str(ip).map_err(|e| anyhow!("invalid ip address {ip}. {e}"))?;
+        match db.lookup(ip) {
+            Some(m) => println!("{ip}\t{}\t{}", m.group, m.value.join("\t")),
+            None => println!("{ip}\t-"),
+        }
+    }
+    Ok(())
+}
+
+// print each network group as the exact list of networks covering it.
+fn print_cidrs(args: &[String]) -> Result<()> {
+    for arg in args {
+        let net = NetGroup::from_str(arg).map_err(|e| anyhow!(e))?;
+        for cidr in net.to_ipnets() {
+            println!("{cidr}");
+        }
+    }
+    Ok(())
+}
+
+// combine list files and print the minimal networks, or ranges with `--ranges`.
+// union and aggregate take every list, intersection the addresses in all of them and
+// difference those of the first list that are in none of the others.
+fn set_operation(args: &[String]) -> Result<()> {
+    let (op, mut lists) = args.split_first().ok_or_else(|| anyhow!(USAGE))?;
+    let ranges = lists.first().is_some_and(|a| a == "--ranges");
+    if ranges {
+        lists = &lists[1..];
+    }
+    let combine: fn(&NetSet, &NetSet) -> NetSet = match op.as_str() {
+        "union" | "aggregate" => NetSet::union,
+        "intersection" => NetSet::intersection,
+        "difference" => NetSet::difference,
+        _ => bail!(USAGE),
+    };
+    let sets = lists
+        .iter()
+        .map(|list| {
+            let source =
+                std::fs::read_to_string(list).map_err(|e| anyhow!("fail to read {list}. {e}"))?;
+            let netgroups = netgroup::parse_list(&source).map_err(|e| anyhow!("{list} {e}"))?;
+            Ok(netgroups.into_iter().collect::<NetSet>())
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let (first, rest) = sets.split_first().ok_or_else(|| anyhow!(USAGE))?;
+    let result = rest
+        .iter()
+        .fold(first.clone(), |acc, set| combine(&acc, set));
+    if result.is_empty() {
+        warn!("no addresses in the result");
+    }
+    if ranges {
+        for net in result.netgroups()? {
+            println!("{net}");
+        }
+    } else {
+        for net in result.ipnets()? {
+            println!("{net}");
+        }
+    }
+    Ok(())
+}
+
+// report the problems of a list file by line. `--write` saves the valid entries merged,
+// one network group per line.
+fn lint_list(args: &[String]) -> Result<()> {
+    let (list, out) = match args {
+        [list] => (list, None),
+        [list, flag, out] if flag == "--write" => (list, Some(out)),
+        _ => bail!(USAGE),
+    };
+    let source = std::fs::read_to_string(list).map_err(|e| anyhow!("fail to read {list}. {e}"))?;
+    let (findings, netgroups) = lint::lint(&source);
+    for finding in &findings {
+        println!("{list} {finding}");
+    }
+    if let Some(out) = out {
+        let cleaned = lint::cleaned(netgroups)?;
+        std::fs::write(out, &cleaned).map_err(|e| anyhow!("fail to write {out}. {e}"))?;
+        println!("wrote {} network groups to {out}", cleaned.lines().count());
+    }
+    if !findings.is_empty() {
+        bail!("{} problems in {list}", findings.len());
+    }
+    Ok(())
+}
+
+fn demo() {
     rangeinclusive_int();
     rangeinclusive_ipaddr();
     ipnet_test();
@@ -20,6 +264,13 @@ fn main() {
         error!("fail to read sample_network_groups.lst file");
         std::process::exit(1);
     };
+    let (findings, _) = lint::lint(&file);
+    if !findings.is_empty() {
+        warn!(
+            "{} problems in sample_network_groups.lst. run `lint` to list them",
+            findings.len()
+        );
+    }
     let networks = file.trim_end().split('\n').collect::<Vec<_>>();
     match SearchTree::build(&networks) {
         Err(e) => println!("{e:?}"),
@@ -83,8 +334,7 @@ fn rangeinclusive_ipaddr() {
     println!("  - max prefix of {ipv6_net} = {ipv6_max_prefix}");
 
     let mut supernet = ipv4_begin_net;
-    loop {
-        let Some(s) = supernet.supernet() else { break; };
+    while let Some(s) = supernet.supernet() {
         if s.contains(&ipv4_end) {
             break;
         }
@@ -0,0 +1,615 @@
+use crate::netgroup::{self, NetGroup};
+use anyhow::{anyhow, Result};
+use ipnet::IpBitAnd;
+use std::{collections::HashMap, net::IpAddr};
+
+// a node holding more entries than this is split into a child map.
+#[cfg(feature = "restructuring")]
+const MAX_NODE_ENTRIES: usize = 16;
+// prefix length added to the parent netmask for each child map.
+#[cfg(feature = "restructuring")]
+const RESTRUCTURING_PREFIX_STEP: u8 = 8;
+
+// network groups and the value attached to each, e.g. a country, an AS number or the
+// name of a feed. `SearchTree<()>` is a plain membership test.
+#[derive(Clone, Debug)]
+pub struct SearchTree<V = ()> {
+    v4: Option<IpNodeMap<V>>,
+    v6: Option<IpNodeMap<V>>,
+}
+
+#[derive(Clone, Debug)]
+struct IpNodeMap<V> {
+    prefix_len: u8,
+    netmask: IpAddr,
+    nodes: HashMap<IpAddr, IpNode<V>>,
+}
+
+#[derive(Clone, Debug)]
+struct IpNode<V> {
+    values: Vec<(NetGroup, V)>,
+    children: Option<Box<IpNodeMap<V>>>,
+}
+
+impl<V> Default for IpNode<V> {
+    fn default() -> Self {
+        Self {
+            values: Vec::new(),
+            children: None,
+        }
+    }
+}
+
+// a network group containing the searched address.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Match<'a, V> {
+    pub group: &'a NetGroup,
+    pub value: &'a V,
+}
+
+impl<V> Clone for Match<'_, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<V> Copy for Match<'_, V> {}
+
+#[cfg(feature = "estimation")]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Estimation {
+    pub depth: usize,
+    pub nodes: usize,
+    pub entries: usize,
+    pub memory: usize,
+}
+
+#[cfg(feature = "estimation")]
+impl std::fmt::Display for Estimation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "depth {}, nodes {}, entries {}, memory {} bytes",
+            self.depth, self.nodes, self.entries, self.memory
+        )
+    }
+}
+
+impl SearchTree {
+    // build search tree from network group lines (see `netgroup::parse_line`).
+    // empty lines and comments are ignored.
+    pub fn build(patterns: &[&str]) -> Result<Option<SearchTree>> {
+        let entries = parse_patterns(patterns)?;
+        Ok(Self::from_netgroups(
+            entries.into_iter().map(|(net, _)| net),
+        ))
+    }
+
+    // build search tree from parsed network groups, e.g. the records of a `NetDb`.
+    pub fn from_netgroups<I>(netgroups: I) -> Option<SearchTree>
+    where
+        I: IntoIterator<Item = NetGroup>,
+    {
+        Self::from_entries(netgroups.into_iter().map(|net| (net, ())))
+    }
+}
+
+impl SearchTree<Option<String>> {
+    // like `build`, with the label after each network group as its value.
+    pub fn build_labeled(patterns: &[&str]) -> Result<Option<Self>> {
+        Ok(Self::from_entries(parse_patterns(patterns)?))
+    }
+}
+
+impl<V> SearchTree<V> {
+    // build search tree from network groups and their values, e.g. the records of a
+    // `NetDb` with their labels.
+    pub fn from_entries<I>(entries: I) -> Option<Self>
+    where
+        I: IntoIterator<Item = (NetGroup, V)>,
+    {
+        let (v4, v6): (Vec<_>, Vec<_>) = entries.into_iter().partition(|(net, _)| net.is_ipv4());
+        let v4 = IpNodeMap::build(v4);
+        let v6 = IpNodeMap::build(v6);
+        if v4.is_none() && v6.is_none() {
+            return None;
+        }
+        Some(SearchTree { v4, v6 })
+    }
+
+    pub fn search(&self, ip: IpAddr) -> bool {
+        self.map(ip).and_then(|m| m.find(ip)).is_some()
+    }
+
+    // every network group containing `ip`, the most specific (smallest) first.
+    pub fn matches(&self, ip: IpAddr) -> Vec<Match<'_, V>> {
+        let mut found = Vec::new();
+        if let Some(map) = self.map(ip) {
+            map.collect(ip, &mut found);
+        }
+        found.sort_by_key(|m| m.group.span());
+        found
+    }
+
+    pub fn most_specific(&self, ip: IpAddr) -> Option<Match<'_, V>> {
+        let mut found = Vec::new();
+        self.map(ip)?.collect(ip, &mut found);
+        found.into_iter().min_by_key(|m| m.group.span())
+    }
+
+    // add one network group without a full rebuild. only a group wider than every group
+    // of its address family changes the netmask of the top map, and rebuilds that map.
+    pub fn insert(&mut self, net: NetGroup, value: V) {
+        let map = if net.is_ipv4() {
+            &mut self.v4
+        } else {
+            &mut self.v6
+        };
+        match map {
+            Some(m) if net.prefix_len() >= m.prefix_len => m.insert(net, value),
+            _ => {
+                let mut entries = map.take().map(IpNodeMap::into_entries).unwrap_or_default();
+                entries.push((net, value));
+                *map = IpNodeMap::build(entries);
+            }
+        }
+    }
+
+    // remove one entry of the network group with the value. false if there is none.
+    pub fn remove(&mut self, net: &NetGroup, value: &V) -> bool
+    where
+        V: PartialEq,
+    {
+        let map = if net.is_ipv4() {
+            &mut self.v4
+        } else {
+            &mut self.v6
+        };
+        let Some(m) = map else {
+            return false;
+        };
+        let removed = m.remove(net, value);
+        if m.nodes.is_empty() {
+            *map = None;
+        }
+        removed
+    }
+
+    fn map(&self, ip: IpAddr) -> Option<&IpNodeMap<V>> {
+        match ip {
+            IpAddr::V4(_) => self.v4.as_ref(),
+            IpAddr::V6(_) => self.v6.as_ref(),
+        }
+    }
+
+    #[cfg(feature = "estimation")]
+    pub fn estimation(&self) -> Estimation {
+        let mut total = Estimation {
+            memory: std::mem::size_of::<Self>(),
+            ..Estimation::default()
+        };
+        for map in [&self.v4, &self.v6].into_iter().flatten() {
+            let e = map.estimation(1);
+            total.depth = total.depth.max(e.depth);
+            total.nodes += e.nodes;
+            total.entries += e.entries;
+            total.memory += e.memory;
+        }
+        total
+    }
+
+    #[cfg(feature = "estimation")]
+    pub fn estimate(&self, title: &str) {
+        println!("{title}: {}", self.estimation());
+    }
+}
+
+impl<V> std::fmt::Display for SearchTree<V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for map in [&self.v4, &self.v6].into_iter().flatten() {
+            map.fmt_with_indent(f, 0)?;
+        }
+        Ok(())
+    }
+}
+
+impl<V> IpNodeMap<V> {
+    fn build(mut entries: Vec<(NetGroup, V)>) -> Option<Self> {
+        entries.sort_by_key(|(net, _)| net.prefix_len());
+        let (prefix_len, netmask) = entries
+            .first()
+            .map(|(f, _)| (f.prefix_len(), f.netmask()))?;
+        #[allow(unused_mut)]
+        let mut map = IpNodeMap::with_netmask(prefix_len, netmask, entries);
+        #[cfg(feature = "restructuring")]
+        map.restructure();
+        Some(map)
+    }
+
+    fn with_netmask(prefix_len: u8, netmask: IpAddr, entries: Vec<(NetGroup, V)>) -> Self {
+        let mut nodes: HashMap<IpAddr, IpNode<V>> = HashMap::new();
+        for (net, value) in entries {
+            if let Some(ip) = net.bitand(netmask) {
+                nodes.entry(ip).or_default().values.push((net, value));
+            }
+        }
+        IpNodeMap {
+            prefix_len,
+            netmask,
+            nodes,
+        }
+    }
+
+    #[cfg(feature = "restructuring")]
+    fn restructure(&mut self) {
+        for node in self.nodes.values_mut() {
+            node.restructure(self.prefix_len, self.netmask);
+        }
+    }
+
+    fn insert(&mut self, net: NetGroup, value: V) {
+        let Some(key) = net.bitand(self.netmask) else {
+            return;
+        };
+        let node = self.nodes.entry(key).or_default();
+        match &mut node.children {
+            Some(children) if net.prefix_len() >= children.prefix_len => {
+                children.insert(net, value);
+            }
+            _ => {
+                node.values.push((net, value));
+                #[cfg(feature = "restructuring")]
+                node.restructure(self.prefix_len, self.netmask);
+            }
+        }
+    }
+
+    // remove one entry and the nodes left empty by it.
+    fn remove(&mut self, net: &NetGroup, value: &V) -> bool
+    where
+        V: PartialEq,
+    {
+        let Some(key) = net.bitand(self.netmask) else {
+            return false;
+        };
+        let Some(node) = self.nodes.get_mut(&key) else {
+            return false;
+        };
+        let removed =
+            if let Some(idx) = node.values.iter().position(|(n, v)| n == net && v == value) {
+                node.values.remove(idx);
+                true
+            } else if let Some(children) = &mut node.children {
+                let removed = children.remove(net, value);
+                if children.nodes.is_empty() {
+                    node.children = None;
+                }
+                removed
+            } else {
+                false
+            };
+        if node.values.is_empty() && node.children.is_none() {
+            self.nodes.remove(&key);
+        }
+        removed
+    }
+
+    fn into_entries(self) -> Vec<(NetGroup, V)> {
+        let mut entries = Vec::new();
+        for node in self.nodes.into_values() {
+            entries.extend(node.values);
+            if let Some(children) = node.children {
+                entries.extend(children.into_entries());
+            }
+        }
+        entries
+    }
+
+    fn find(&self, ip: IpAddr) -> Option<&(NetGroup, V)> {
+        let masked = network_by_ipaddr(ip, self.netmask)?;
+        let node = self.nodes.get(&masked)?;
+        node.values
+            .iter()
+            .find(|(net, _)| net.contains(ip))
+            .or_else(|| node.children.as_ref().and_then(|c| c.find(ip)))
+    }
+
+    // the matches in this node and its children.
+    fn collect<'a>(&'a self, ip: IpAddr, found: &mut Vec<Match<'a, V>>) {
+        let Some(node) = network_by_ipaddr(ip, self.netmask).and_then(|m| self.nodes.get(&m))
+        else {
+            return;
+        };
+        found.extend(
+            node.values
+                .iter()
+                .filter(|(net, _)| net.contains(ip))
+                .map(|(group, value)| Match { group, value }),
+        );
+        if let Some(children) = &node.children {
+            children.collect(ip, found);
+        }
+    }
+
+    #[cfg(feature = "estimation")]
+    fn estimation(&self, depth: usize) -> Estimation {
+        let mut total = Estimation {
+            depth,
+            nodes: self.nodes.len(),
+            entries: 0,
+            memory: std::mem::size_of::<Self>()
+                + self.nodes.capacity()
+                    * (std::mem::size_of::<IpAddr>() + std::mem::size_of::<IpNode<V>>()),
+        };
+        for node in self.nodes.values() {
+            total.entries += node.values.len();
+            total.memory += node.values.capacity() * std::mem::size_of::<(NetGroup, V)>();
+            if let Some(children) = &node.children {
+                let e = children.estimation(depth + 1);
+                total.depth = total.depth.max(e.depth);
+                total.nodes += e.nodes;
+                total.entries += e.entries;
+                total.memory += e.memory;
+            }
+        }
+        total
+    }
+
+    fn fmt_with_indent(&self, f: &mut std::fmt::Formatter<'_>, indent: usize) -> std::fmt::Result {
+        let mut keys = self.nodes.keys().collect::<Vec<_>>();
+        keys.sort();
+        for key in keys {
+            let Some(node) = self.nodes.get(key) else {
+                continue;
+            };
+            writeln!(
+                f,
+                "{:indent$}{key}/{} {:?}",
+                "",
+                self.prefix_len,
+                node.values
+                    .iter()
+                    .map(|(net, _)| net.to_string())
+                    .collect::<Vec<_>>()
+            )?;
+            if let Some(children) = &node.children {
+                children.fmt_with_indent(f, indent + 2)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<V> IpNode<V> {
+    // move the entries that are more specific than the child netmask into a
+    // child map, and repeat until every node is small enough.
+    #[cfg(feature = "restructuring")]
+    fn restructure(&mut self, prefix_len: u8, netmask: IpAddr) {
+        if self.values.len() <= MAX_NODE_ENTRIES {
+            return;
+        }
+        let child_prefix_len = prefix_len
+            .saturating_add(RESTRUCTURING_PREFIX_STEP)
+            .min(max_prefix_len(netmask));
+        if child_prefix_len <= prefix_len {
+            return;
+        }
+        let Some(child_netmask) = netmask_by_prefix_len(netmask, child_prefix_len) else {
+            return;
+        };
+        let (stay, down): (Vec<_>, Vec<_>) = std::mem::take(&mut self.values)
+            .into_iter()
+            .partition(|(net, _)| net.prefix_len() < child_prefix_len);
+        self.values = stay;
+        if down.is_empty() {
+            return;
+        }
+        // entries inserted after the build join the existing child map
+        if let Some(children) = &mut self.children {
+            for (net, value) in down {
+                children.insert(net, value);
+            }
+            return;
+        }
+        let mut children = IpNodeMap::with_netmask(child_prefix_len, child_netmask, down);
+        children.restructure();
+        self.children = Some(Box::new(children));
+    }
+}
+
+fn parse_patterns(patterns: &[&str]) -> Result<Vec<(NetGroup, Option<String>)>> {
+    let mut entries = Vec::new();
+    for pattern in patterns {
+        if let Some(entry) = netgroup::parse_line(pattern).map_err(|e| anyhow!(e))? {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(feature = "restructuring")]
+fn max_prefix_len(netmask: IpAddr) -> u8 {
+    match netmask {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+#[cfg(feature = "restructuring")]
+fn netmask_by_prefix_len(netmask: IpAddr, prefix_len: u8) -> Option<IpAddr> {
+    match netmask {
+        IpAddr::V4(_) => ipnet::Ipv4Net::new(std::net::Ipv4Addr::UNSPECIFIED, prefix_len)
+            .ok()
+            .map(|n| IpAddr::V4(n.netmask())),
+        IpAddr::V6(_) => ipnet::Ipv6Net::new(std::net::Ipv6Addr::UNSPECIFIED, prefix_len)
+            .ok()
+            .map(|n| IpAddr::V6(n.netmask())),
+    }
+}
+
+fn network_by_ipaddr(ipaddr: IpAddr, netmask: IpAddr) -> Option<IpAddr> {
+    match (ipaddr, netmask) {
+        (IpAddr::V4(x), IpAddr::V4(y)) => Some(IpAddr::V4(x.bitand(y))),
+        (IpAddr::V6(x), IpAddr::V6(y)) => Some(IpAddr::V6(x.bitand(y))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SearchTree;
+    use crate::netgroup::NetGroup;
+
+    #[test]
+    fn dual_stack_search() {
+        let networks = vec!["10.0.0.0/8", "fd00::/8", "2001:db8::1..=2001:db8::ff"];
+        let tree = SearchTree::build(&networks).unwrap().unwrap();
+        assert!(tree.search("10.1.2.3".parse().unwrap()));
+        assert!(tree.search("fd12::1".parse().unwrap()));
+        assert!(tree.search("2001:db8::80".parse().unwrap()));
+        assert!(!tree.search("2001:db8::100".parse().unwrap()));
+        assert!(!tree.search("11.0.0.1".parse().unwrap()));
+    }
+
+    // ranges alone are keyed by the network covering all of them, with no shorter
+    // network in the list to widen the netmask.
+    #[test]
+    fn range_only() {
+        let tree = SearchTree::build(&["11.10.1.100..=11.10.1.109"])
+            .unwrap()
+            .unwrap();
+        assert!(tree.search("11.10.1.105".parse().unwrap()));
+        assert!(tree.search("11.10.1.109".parse().unwrap()));
+        assert!(!tree.search("11.10.1.110".parse().unwrap()));
+
+        let tree = SearchTree::build(&["2001:db8::1..=2001:db8::ff"])
+            .unwrap()
+            .unwrap();
+        assert!(tree.search("2001:db8::ff".parse().unwrap()));
+        assert!(!tree.search("2001:db8::100".parse().unwrap()));
+    }
+
+    #[test]
+    fn exact_ranges() {
+        let networks = ["11.10.1.100..=11.10.1.109", "2001:db8::1..=2001:db8::ff"]
+            .iter()
+            .flat_map(|s| s.parse::<NetGroup>().unwrap().to_ipnets())
+            .map(NetGroup::IpNet)
+            .collect::<Vec<_>>();
+        assert_eq!(networks.len(), 11);
+        let tree = SearchTree::from_netgroups(networks).unwrap();
+        assert!(!tree.search("11.10.1.99".parse().unwrap()));
+        assert!(tree.search("11.10.1.100".parse().unwrap()));
+        assert!(tree.search("11.10.1.109".parse().unwrap()));
+        assert!(!tree.search("11.10.1.110".parse().unwrap()));
+        assert!(!tree.search("2001:db8::".parse().unwrap()));
+        assert!(tree.search("2001:db8::ff".parse().unwrap()));
+    }
+
+    #[test]
+    fn payload_matches() {
+        let entries = [
+            ("10.0.0.0/8", "private"),
+            ("10.1.0.0/16", "office"),
+            ("10.1.2.10..=10.1.2.20", "printers"),
+            ("2001:db8::/32", "documentation"),
+        ]
+        .map(|(net, name)| (net.parse::<NetGroup>().unwrap(), name));
+        let tree = SearchTree::from_entries(entries).unwrap();
+
+        let ip = "10.1.2.15".parse().unwrap();
+        let found = tree
+            .matches(ip)
+            .iter()
+            .map(|m| (m.group.to_string(), *m.value))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            vec![
+                ("10.1.2.10..=10.1.2.20".to_string(), "printers"),
+                ("10.1.0.0/16".to_string(), "office"),
+                ("10.0.0.0/8".to_string(), "private"),
+            ]
+        );
+        let best = tree.most_specific("10.1.9.9".parse().unwrap()).unwrap();
+        assert_eq!(
+            (best.group.to_string(), *best.value),
+            ("10.1.0.0/16".to_string(), "office")
+        );
+        assert_eq!(
+            tree.most_specific("2001:db8::1".parse().unwrap())
+                .map(|m| *m.value),
+            Some("documentation")
+        );
+        assert!(tree.matches("11.0.0.1".parse().unwrap()).is_empty());
+        assert!(tree.most_specific("fd00::1".parse().unwrap()).is_none());
+
+        let labeled = SearchTree::build_labeled(&["10.0.0.0/8 private", "1.2.3.0/24 ; SBL123"])
+            .unwrap()
+            .unwrap();
+        let m = labeled.most_specific("1.2.3.4".parse().unwrap()).unwrap();
+        assert_eq!(m.value.as_deref(), Some("SBL123"));
+    }
+
+    #[test]
+    fn incremental_updates() {
+        let mut tree = SearchTree::build_labeled(&["10.1.0.0/16 office"])
+            .unwrap()
+            .unwrap();
+        let net = |s: &str| s.parse::<NetGroup>().unwrap();
+        let label = |s: &str| Some(s.to_string());
+
+        tree.insert(net("10.1.2.0/24"), label("lab"));
+        // wider than the top map
+        tree.insert(net("10.0.0.0/8"), label("private"));
+        tree.insert(net("fd00::/8"), label("ula"));
+        for i in 0..=255 {
+            tree.insert(net(&format!("10.1.{i}.128/25")), label("hosts"));
+        }
+        let best = |tree: &SearchTree<Option<String>>, ip: &str| {
+            tree.most_specific(ip.parse().unwrap())
+                .and_then(|m| m.value.clone())
+        };
+        assert_eq!(best(&tree, "10.1.2.1"), label("lab"));
+        assert_eq!(best(&tree, "10.1.2.200"), label("hosts"));
+        assert_eq!(best(&tree, "10.1.9.1"), label("office"));
+        assert_eq!(best(&tree, "10.9.9.9"), label("private"));
+        assert_eq!(best(&tree, "fd00::1"), label("ula"));
+        assert_eq!(tree.matches("10.1.2.200".parse().unwrap()).len(), 4);
+
+        assert!(!tree.remove(&net("10.1.2.0/24"), &label("office")));
+        assert!(tree.remove(&net("10.1.2.0/24"), &label("lab")));
+        assert_eq!(best(&tree, "10.1.2.1"), label("office"));
+        for i in 0..=255 {
+            assert!(tree.remove(&net(&format!("10.1.{i}.128/25")), &label("hosts")));
+        }
+        assert_eq!(best(&tree, "10.1.2.200"), label("office"));
+        assert!(tree.remove(&net("fd00::/8"), &label("ula")));
+        assert!(!tree.search("fd00::1".parse().unwrap()));
+        assert!(tree.remove(&net("10.0.0.0/8"), &label("private")));
+        assert!(tree.remove(&net("10.1.0.0/16"), &label("office")));
+        assert!(!tree.search("10.1.9.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn invalid_network_group() {
+        assert!(SearchTree::build(&["10.0.0.0/8", "10.0.0.300"]).is_err());
+        assert!(matches!(SearchTree::build(&["", "# comment"]), Ok(None)));
+    }
+
+    #[cfg(all(feature = "restructuring", feature = "estimation"))]
+    #[test]
+    fn restructuring_crowded_node() {
+        let networks = (0..=255)
+            .map(|i| format!("10.{i}.1.0/24"))
+            .chain(std::iter::once("10.0.0.0/8".to_string()))
+            .collect::<Vec<_>>();
+        let networks = networks.iter().map(String::as_str).collect::<Vec<_>>();
+        let tree = SearchTree::build(&networks).unwrap().unwrap();
+        let estimation = tree.estimation();
+        assert_eq!(estimation.entries, 257);
+        assert!(estimation.depth > 1);
+        assert!(tree.search("10.200.1.7".parse().unwrap()));
+        assert!(tree.search("10.200.2.7".parse().unwrap()));
+    }
+}
@@ -0,0 +1,428 @@
+use crate::{
+    netgroup::{self, NetGroup},
+    netset::{self, NetSet},
+};
+use anyhow::{anyhow, bail, Result};
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+// compiled network group database
+//
+// header (little endian)
+//   0  magic "NGDB"
+//   4  version u16
+//   6  record length u16
+//   8  number of records u64
+//  16  number of ipv4 intervals u64
+//  24  number of ipv6 intervals u64
+//  32  length of the label table u64
+//  40  hash of the source list u64
+//  48  checksum of the records, the intervals and the labels u64
+// records
+//   0  kind u8 (0: ipv4 network, 1: ipv6 network, 2: ipv4 range, 3: ipv6 range)
+//   1  prefix length u8. the covering network for ranges.
+//   2  network address or range start [u8; 16]
+//  18  range end [u8; 16]. zero for networks.
+//  34  label offset in the label table u32
+//  38  label length u16. zero for no label.
+// intervals: the records merged into sorted disjoint intervals, ipv4 first. lookups
+// search them in place.
+//   0  first address u128 (big endian, ipv4 in the low 32 bits)
+//  16  last address u128
+// label table: the distinct labels in utf-8, one after another.
+//
+// the hash and the checksum are FNV-1a. they find accidental corruption and a changed
+// source list, not deliberate tampering.
+const MAGIC: [u8; 4] = *b"NGDB";
+pub const VERSION: u16 = 3;
+const HEADER_LEN: usize = 56;
+const RECORD_LEN: usize = 40;
+const INTERVAL_LEN: usize = 32;
+
+const KIND_V4_NET: u8 = 0;
+const KIND_V6_NET: u8 = 1;
+const KIND_V4_RANGE: u8 = 2;
+const KIND_V6_RANGE: u8 = 3;
+
+// records are decoded from the file contents when they are read.
+#[derive(Clone, Debug)]
+pub struct NetDb {
+    bytes: Vec<u8>,
+    len: usize,
+    v4_intervals: usize,
+    v6_intervals: usize,
+    labels_len: usize,
+    source_hash: u64,
+}
+
+// parse a network group list and compile it into database bytes.
+// empty lines and lines starting with '#' are ignored, as `SearchTree::build` does.
+// the text after each group is kept as its label, as `SearchTree::build_labeled` does.
+// with `exact`, ranges are stored as the exact list of networks covering them.
+pub fn compile(source: &str, exact: bool) -> Result<Vec<u8>> {
+    let mut entries = netgroup::parse_labeled_list(source)?;
+    if exact {
+        entries = entries
+            .iter()
+            .flat_map(|(net, label)| {
+                net.to_ipnets()
+                    .into_iter()
+                    .map(|x| (NetGroup::IpNet(x), label.clone()))
+            })
+            .collect();
+    }
+    entries.sort();
+
+    let mut labels = Vec::new();
+    let mut offsets = HashMap::new();
+    let mut records = Vec::with_capacity(entries.len() * RECORD_LEN);
+    for (net, label) in &entries {
+        let label = label.as_deref().unwrap_or_default();
+        let Ok(len) = u16::try_from(label.len()) else {
+            bail!("label of {net} is longer than {} bytes", u16::MAX);
+        };
+        let offset = match offsets.get(label) {
+            Some(offset) => *offset,
+            None => {
+                let Ok(offset) = u32::try_from(labels.len()) else {
+                    bail!("labels are longer than {} bytes", u32::MAX);
+                };
+                labels.extend_from_slice(label.as_bytes());
+                offsets.insert(label, offset);
+                offset
+            }
+        };
+        encode(net, (offset, len), &mut records);
+    }
+
+    let set = entries
+        .iter()
+        .map(|(net, _)| net.clone())
+        .collect::<NetSet>();
+    let (v4, v6) = (set.intervals(true), set.intervals(false));
+    let mut body = records;
+    body.reserve((v4.len() + v6.len()) * INTERVAL_LEN + labels.len());
+    for (start, end) in v4.iter().chain(v6) {
+        body.extend_from_slice(&start.to_be_bytes());
+        body.extend_from_slice(&end.to_be_bytes());
+    }
+    body.extend_from_slice(&labels);
+    let mut bytes = Vec::with_capacity(HEADER_LEN + body.len());
+    bytes.extend_from_slice(&MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(RECORD_LEN as u16).to_le_bytes());
+    for n in [entries.len(), v4.len(), v6.len(), labels.len()] {
+        bytes.extend_from_slice(&(n as u64).to_le_bytes());
+    }
+    bytes.extend_from_slice(&fnv1a(source.as_bytes()).to_le_bytes());
+    bytes.extend_from_slice(&fnv1a(&body).to_le_bytes());
+    bytes.extend_from_slice(&body);
+    Ok(bytes)
+}
+
+impl NetDb {
+    pub fn open(path: &str) -> Result<Self> {
+        let bytes = std::fs::read(path).map_err(|e| anyhow!("fail to read {path}. {e}"))?;
+        Self::from_bytes(bytes)
+    }
+
+    // check the header and the checksum of the records and the intervals.
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        if bytes.len() < HEADER_LEN || bytes[..4] != MAGIC {
+            bail!("not a network group database");
+        }
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if version != VERSION {
+            bail!("unsupported database version {version}. expected {VERSION}");
+        }
+        let record_len = u16::from_le_bytes([bytes[6], bytes[7]]);
+        if usize::from(record_len) != RECORD_LEN {
+            bail!("invalid record length {record_len}");
+        }
+        let len = usize::try_from(read_u64(&bytes, 8))?;
+        let v4_intervals = usize::try_from(read_u64(&bytes, 16))?;
+        let v6_intervals = usize::try_from(read_u64(&bytes, 24))?;
+        let labels_len = usize::try_from(read_u64(&bytes, 32))?;
+        let body = len.checked_mul(RECORD_LEN).and_then(|records| {
+            v4_intervals
+                .checked_add(v6_intervals)?
+                .checked_mul(INTERVAL_LEN)?
+                .checked_add(records)?
+                .checked_add(labels_len)
+        });
+        if body.and_then(|n| n.checked_add(HEADER_LEN)) != Some(bytes.len()) {
+            bail!("database is truncated or has trailing data");
+        }
+        if fnv1a(&bytes[HEADER_LEN..]) != read_u64(&bytes, 48) {
+            bail!("checksum mismatch");
+        }
+        let source_hash = read_u64(&bytes, 40);
+        Ok(Self {
+            bytes,
+            len,
+            v4_intervals,
+            v6_intervals,
+            labels_len,
+            source_hash,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // whether the database was compiled from a different list.
+    pub fn is_stale(&self, source: &str) -> bool {
+        fnv1a(source.as_bytes()) != self.source_hash
+    }
+
+    pub fn get(&self, idx: usize) -> Result<NetGroup> {
+        decode(self.record(idx)?).map_err(|e| anyhow!("invalid record {idx}. {e}"))
+    }
+
+    // the label of a record, read from the label table in place.
+    pub fn label(&self, idx: usize) -> Result<Option<&str>> {
+        let record = self.record(idx)?;
+        let offset = usize::try_from(u32::from_le_bytes([
+            record[34], record[35], record[36], record[37],
+        ]))?;
+        let len = usize::from(u16::from_le_bytes([record[38], record[39]]));
+        if len == 0 {
+            return Ok(None);
+        }
+        let labels = &self.bytes[self.bytes.len() - self.labels_len..];
+        let label = offset
+            .checked_add(len)
+            .and_then(|end| labels.get(offset..end))
+            .ok_or_else(|| anyhow!("invalid record {idx}. label is out of the label table"))?;
+        let label = std::str::from_utf8(label)
+            .map_err(|e| anyhow!("invalid record {idx}. label is not utf-8. {e}"))?;
+        Ok(Some(label))
+    }
+
+    // the network groups with their labels, as `SearchTree::from_entries` takes them.
+    pub fn entries(&self) -> Result<Vec<(NetGroup, Option<String>)>> {
+        (0..self.len)
+            .map(|idx| Ok((self.get(idx)?, self.label(idx)?.map(ToString::to_string))))
+            .collect()
+    }
+
+    fn record(&self, idx: usize) -> Result<&[u8]> {
+        if idx >= self.len {
+            bail!("record {idx} is out of range");
+        }
+        let offset = HEADER_LEN + idx * RECORD_LEN;
+        Ok(&self.bytes[offset..offset + RECORD_LEN])
+    }
+
+    // whether a network group contains `ip`, by a binary search of the intervals in the
+    // file contents. nothing is decoded or built.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let start = HEADER_LEN + self.len * RECORD_LEN;
+        let (skip, count) = if ip.is_ipv4() {
+            (0, self.v4_intervals)
+        } else {
+            (self.v4_intervals, self.v6_intervals)
+        };
+        let offset = start + skip * INTERVAL_LEN;
+        let (intervals, _) =
+            self.bytes[offset..offset + count * INTERVAL_LEN].as_chunks::<INTERVAL_LEN>();
+        let n = netset::to_u128(ip);
+        let bound = |interval: &[u8; INTERVAL_LEN], at: usize| {
+            let mut buf = [0; 16];
+            buf.copy_from_slice(&interval[at..at + 16]);
+            u128::from_be_bytes(buf)
+        };
+        // the last interval starting at or before `ip`
+        let idx = intervals.partition_point(|i| bound(i, 0) <= n);
+        idx > 0 && bound(&intervals[idx - 1], 16) >= n
+    }
+}
+
+// `label` is the offset and the length of the label in the label table.
+fn encode(net: &NetGroup, (label_offset, label_len): (u32, u16), buf: &mut Vec<u8>) {
+    let (kind, prefix_len, start, end) = match net {
+        NetGroup::IpNet(x) => (
+            if x.addr().is_ipv4() {
+                KIND_V4_NET
+            } else {
+                KIND_V6_NET
+            },
+            x.prefix_len(),
+            x.addr(),
+            None,
+        ),
+        NetGroup::IpRange((x, range)) => (
+            if x.addr().is_ipv4() {
+                KIND_V4_RANGE
+            } else {
+                KIND_V6_RANGE
+            },
+            x.prefix_len(),
+            *range.start(),
+            Some(*range.end()),
+        ),
+    };
+    buf.push(kind);
+    buf.push(prefix_len);
+    buf.extend_from_slice(&addr_bytes(start));
+    buf.extend_from_slice(&end.map_or([0; 16], addr_bytes));
+    buf.extend_from_slice(&label_offset.to_le_bytes());
+    buf.extend_from_slice(&label_len.to_le_bytes());
+}
+
+fn decode(record: &[u8]) -> Result<NetGroup> {
+    let (kind, prefix_len) = (record[0], record[1]);
+    let v4 = kind == KIND_V4_NET || kind == KIND_V4_RANGE;
+    let addr = |bytes: &[u8]| -> IpAddr {
+        if v4 {
+            IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]))
+        } else {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&bytes[..16]);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+    };
+    let start = addr(&record[2..18]);
+    let net = match start {
+        IpAddr::V4(x) => IpNet::V4(Ipv4Net::new(x, prefix_len)?),
+        IpAddr::V6(x) => IpNet::V6(Ipv6Net::new(x, prefix_len)?),
+    };
+    match kind {
+        KIND_V4_NET | KIND_V6_NET => Ok(NetGroup::IpNet(net)),
+        KIND_V4_RANGE | KIND_V6_RANGE => {
+            let end = addr(&record[18..34]);
+            if end < start {
+                bail!("reversed range {start}..={end}");
+            }
+            Ok(NetGroup::IpRange((net.trunc(), start..=end)))
+        }
+        _ => bail!("unknown record kind {kind}"),
+    }
+}
+
+// ipv4 addresses take the first 4 bytes.
+fn addr_bytes(addr: IpAddr) -> [u8; 16] {
+    let mut bytes = [0; 16];
+    match addr {
+        IpAddr::V4(x) => bytes[..4].copy_from_slice(&x.octets()),
+        IpAddr::V6(x) => bytes = x.octets(),
+    }
+    bytes
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(buf)
+}
+
+// 64-bit FNV-1a
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compile, NetDb, HEADER_LEN};
+    use crate::{mapper::SearchTree, netgroup::NetGroup};
+
+    const SOURCE: &str = "# blocklist
+5.188.10.0/23 ; SBL123
+11.10.1.100..=11.10.1.109 scanners
+127.0.0.1
+2001:db8::1..=2001:db8::ff scanners
+fd00::/8
+";
+
+    #[test]
+    fn compile_and_load() {
+        let db = NetDb::from_bytes(compile(SOURCE, false).unwrap()).unwrap();
+        assert_eq!(db.len(), 5);
+        assert!(!db.is_stale(SOURCE));
+        assert!(db.is_stale("5.188.10.0/23\n"));
+
+        let entries = db.entries().unwrap();
+        assert_eq!(entries[0].0.to_string(), "5.188.10.0/23");
+        assert_eq!(entries[1].0.to_string(), "11.10.1.100..=11.10.1.109");
+        assert_eq!(db.label(0).unwrap(), Some("SBL123"));
+        assert_eq!(db.label(1).unwrap(), Some("scanners"));
+        assert_eq!(db.label(2).unwrap(), None);
+        // a label is stored once
+        assert_eq!(db.labels_len, "SBL123scanners".len());
+        let tree = SearchTree::from_entries(entries).unwrap();
+        let label = |ip: &str| {
+            tree.most_specific(ip.parse().unwrap())
+                .and_then(|m| m.value.clone())
+        };
+        assert_eq!(label("2001:db8::80").as_deref(), Some("scanners"));
+        assert_eq!(label("fd00::1"), None);
+        for (ip, found) in [
+            ("11.10.1.105", true),
+            ("11.10.1.100", true),
+            ("11.10.1.109", true),
+            ("11.10.1.110", false),
+            ("5.188.11.255", true),
+            ("5.188.12.0", false),
+            ("0.0.0.0", false),
+            ("2001:db8::80", true),
+            ("2001:db8::", false),
+            ("fd00::1", true),
+            ("ffff::1", false),
+            ("127.0.0.1", true),
+            ("127.0.0.2", false),
+        ] {
+            let ip = ip.parse().unwrap();
+            assert_eq!(db.contains(ip), found, "{ip}");
+            assert_eq!(tree.search(ip), found, "{ip}");
+        }
+        // an ipv4 address is not found in the ipv6 intervals
+        assert!(!db.contains("::b0a:164".parse().unwrap()));
+    }
+
+    #[test]
+    fn exact_ranges() {
+        let db = NetDb::from_bytes(compile(SOURCE, true).unwrap()).unwrap();
+        // 11.10.1.100/30, 11.10.1.104/30, 11.10.1.108/31 and 2001:db8::1/128 ~ 2001:db8::80/121
+        assert_eq!(db.len(), 14);
+        let entries = db.entries().unwrap();
+        assert!(entries.iter().all(|(n, _)| matches!(n, NetGroup::IpNet(_))));
+        assert!(entries
+            .iter()
+            .filter(|(n, _)| n.to_string().starts_with("11.10.1."))
+            .all(|(_, label)| label.as_deref() == Some("scanners")));
+        assert!(db.contains("11.10.1.109".parse().unwrap()));
+        assert!(!db.contains("11.10.1.110".parse().unwrap()));
+    }
+
+    #[test]
+    fn corrupted_database() {
+        let bytes = compile(SOURCE, false).unwrap();
+        let mut flipped = bytes.clone();
+        flipped[HEADER_LEN + 3] ^= 1;
+        assert!(NetDb::from_bytes(flipped)
+            .unwrap_err()
+            .to_string()
+            .contains("checksum"));
+        assert!(NetDb::from_bytes(bytes[..bytes.len() - 1].to_vec()).is_err());
+        // a label offset past the table, with the checksum fixed up
+        let mut label = bytes.clone();
+        label[HEADER_LEN + 34] = 0xff;
+        let checksum = super::fnv1a(&label[HEADER_LEN..]);
+        label[48..56].copy_from_slice(&checksum.to_le_bytes());
+        let db = NetDb::from_bytes(label).unwrap();
+        assert!(db.label(0).unwrap_err().to_string().contains("label"));
+        let mut version = bytes;
+        version[4] = 9;
+        assert!(NetDb::from_bytes(version).is_err());
+        assert!(compile("10.0.0.0/8\n10.0.0.300\n", false)
+            .unwrap_err()
+            .to_string()
+            .starts_with("line 2"));
+    }
+}
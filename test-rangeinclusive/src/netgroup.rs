@@ -27,13 +27,7 @@ impl Ord for NetGroup {
 
 impl PartialOrd for NetGroup {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        let (left, right) = match (self, other) {
-            (NetGroup::IpNet(x), NetGroup::IpNet(y)) => (x.addr(), y.addr()),
-            (NetGroup::IpRange((_, x)), NetGroup::IpRange((_, y))) => (*x.start(), *y.start()),
-            (NetGroup::IpNet(x), NetGroup::IpRange((_, y))) => (x.addr(), *y.start()),
-            (NetGroup::IpRange((_, x)), NetGroup::IpNet(y)) => (*x.start(), y.addr()),
-        };
-        Some(left.cmp(&right))
+        Some(self.cmp(other))
     }
 }
 
@@ -50,29 +44,110 @@ impl std::fmt::Display for NetGroup {
     }
 }
 
-fn rangeinclusive_to_ipnet(v: &RangeInclusive<IpAddr>) -> Result<IpNet> {
+pub fn rangeinclusive_to_ipnet(v: &RangeInclusive<IpAddr>) -> Result<IpNet> {
     let start = v.start();
     let end = v.end();
     let max_prefix_len = IpNet::new(*start, 32)?.max_prefix_len();
-    let Ok(mut find_super_net) =
-        IpNet::new(*start, max_prefix_len) else { bail!("fail to find super net."); };
-    loop {
-        let Some(s) = find_super_net.supernet() else { bail!("fail to find super net."); };
-        if s.contains(end) {
-            break;
-        }
+    let Ok(mut find_super_net) = IpNet::new(*start, max_prefix_len) else {
+        bail!("fail to find super net.");
+    };
+    while !find_super_net.contains(end) {
+        let Some(s) = find_super_net.supernet() else {
+            bail!("fail to find super net.");
+        };
         find_super_net = s;
     }
     Ok(find_super_net)
 }
 
+// the minimal list of networks that covers exactly the addresses of the range.
+// e.g. 11.10.1.100..=11.10.1.109 => 11.10.1.100/30, 11.10.1.104/30, 11.10.1.108/31
+pub fn rangeinclusive_to_ipnets(v: &RangeInclusive<IpAddr>) -> Result<Vec<IpNet>> {
+    let (start, end, bits) = match (v.start(), v.end()) {
+        (IpAddr::V4(s), IpAddr::V4(e)) => {
+            (u128::from(u32::from(*s)), u128::from(u32::from(*e)), 32)
+        }
+        (IpAddr::V6(s), IpAddr::V6(e)) => (u128::from(*s), u128::from(*e), 128),
+        _ => bail!("mixed address families in range {v:?}"),
+    };
+    if start > end {
+        bail!("reversed range {v:?}");
+    }
+    // addresses in a block of 2^n addresses, minus one
+    let span = |n: u32| {
+        if n >= 128 {
+            u128::MAX
+        } else {
+            (1u128 << n) - 1
+        }
+    };
+    let mut nets = Vec::new();
+    let mut cur = start;
+    loop {
+        // the largest block aligned at `cur` that does not pass `end`
+        let mut n = cur.trailing_zeros().min(bits);
+        while span(n) > end - cur {
+            n -= 1;
+        }
+        let prefix_len = (bits - n) as u8;
+        nets.push(match v.start() {
+            IpAddr::V4(_) => IpNet::V4(Ipv4Net::new(Ipv4Addr::from(cur as u32), prefix_len)?),
+            IpAddr::V6(_) => IpNet::V6(Ipv6Net::new(Ipv6Addr::from(cur), prefix_len)?),
+        });
+        match (cur + span(n)).checked_add(1) {
+            Some(next) if next <= end => cur = next,
+            _ => break,
+        }
+    }
+    Ok(nets)
+}
+
+// a network if the range is exactly one, a range otherwise.
+pub fn netgroup_from_range(range: RangeInclusive<IpAddr>) -> Result<NetGroup> {
+    let nets = rangeinclusive_to_ipnets(&range)?;
+    Ok(if let [net] = nets.as_slice() {
+        NetGroup::IpNet(*net)
+    } else {
+        NetGroup::IpRange((rangeinclusive_to_ipnet(&range)?, range))
+    })
+}
+
 impl NetGroup {
+    // networks covering exactly the addresses of the group.
+    pub fn to_ipnets(&self) -> Vec<IpNet> {
+        match self {
+            NetGroup::IpNet(x) => vec![*x],
+            NetGroup::IpRange((x, range)) => rangeinclusive_to_ipnets(range).unwrap_or(vec![*x]),
+        }
+    }
+
+    // first and last address of the group.
+    pub fn bounds(&self) -> (IpAddr, IpAddr) {
+        match self {
+            NetGroup::IpNet(x) => (x.network(), x.broadcast()),
+            NetGroup::IpRange((_, x)) => (*x.start(), *x.end()),
+        }
+    }
+
+    // number of addresses in the group minus one. smaller groups are more specific.
+    pub fn span(&self) -> u128 {
+        match self.bounds() {
+            (IpAddr::V4(s), IpAddr::V4(e)) => u128::from(u32::from(e).saturating_sub(u32::from(s))),
+            (IpAddr::V6(s), IpAddr::V6(e)) => u128::from(e).saturating_sub(u128::from(s)),
+            _ => u128::MAX,
+        }
+    }
+
     fn network(&self) -> &IpNet {
         match self {
             NetGroup::IpNet(x) | NetGroup::IpRange((x, _)) => x,
         }
     }
 
+    pub fn is_ipv4(&self) -> bool {
+        matches!(self.network(), IpNet::V4(_))
+    }
+
     pub fn prefix_len(&self) -> u8 {
         self.network().prefix_len()
     }
@@ -97,49 +172,349 @@ impl NetGroup {
     }
 }
 
-fn rangeinclusive_ipaddr_from_str(range: &str) -> Result<RangeInclusive<IpAddr>> {
-    let s = range.split("..=").collect::<Vec<_>>();
-    if s.len() == 2 {
-        if let Some(first) = s.first() {
-            if let Ok(first) = IpAddr::from_str(first) {
-                if let Some(last) = s.last() {
-                    if let Ok(last) = IpAddr::from_str(last) {
-                        return Ok(RangeInclusive::new(first, last));
-                    }
-                }
+// what is wrong with a network group string.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum NetGroupError {
+    Empty,
+    InvalidAddress(String),
+    InvalidPrefixLen(String),
+    // dotted netmask whose one bits are not contiguous, e.g. 255.0.255.0
+    NonContiguousNetmask(String),
+    InvalidWildcard(String),
+    InvalidRange(String),
+    ReversedRange(IpAddr, IpAddr),
+    MixedFamilies(IpAddr, IpAddr),
+}
+
+impl std::fmt::Display for NetGroupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetGroupError::Empty => write!(f, "empty network group"),
+            NetGroupError::InvalidAddress(s) => write!(f, "invalid ip address {s}"),
+            NetGroupError::InvalidPrefixLen(s) => write!(f, "invalid prefix length {s}"),
+            NetGroupError::NonContiguousNetmask(s) => write!(f, "non-contiguous netmask {s}"),
+            NetGroupError::InvalidWildcard(s) => write!(f, "invalid wildcard {s}"),
+            NetGroupError::InvalidRange(s) => write!(f, "invalid range {s}"),
+            NetGroupError::ReversedRange(start, end) => {
+                write!(f, "reversed range {start}..={end}")
+            }
+            NetGroupError::MixedFamilies(start, end) => {
+                write!(f, "mixed address families in range {start}..={end}")
             }
         }
     }
-    Err(anyhow!("invalid RangeInclusive<IpAddr> value {}", range))
 }
 
-// convert IpAddr, IpNet, RangeInclusive<IpAddr> string to NetGroup
+impl std::error::Error for NetGroupError {}
+
+fn parse_addr(s: &str) -> Result<IpAddr, NetGroupError> {
+    IpAddr::from_str(s.trim()).map_err(|_| NetGroupError::InvalidAddress(s.trim().to_string()))
+}
+
+// "1.2.3.4..=1.2.3.9", or the two sides of "1.2.3.4-1.2.3.9".
+// the end may give the last part of the start address only: "1.2.3.4-9", "2001:db8::1-ff".
+fn rangeinclusive_ipaddr_from_str(
+    start: &str,
+    end: &str,
+) -> Result<RangeInclusive<IpAddr>, NetGroupError> {
+    let first = parse_addr(start)?;
+    let end = end.trim();
+    let last = match first {
+        IpAddr::V4(x) if !end.contains(['.', ':']) => {
+            let mut octets = x.octets();
+            octets[3] = end
+                .parse::<u8>()
+                .map_err(|_| NetGroupError::InvalidRange(format!("{start}-{end}")))?;
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        IpAddr::V6(x) if !end.contains(['.', ':']) => {
+            let mut segments = x.segments();
+            segments[7] = u16::from_str_radix(end, 16)
+                .map_err(|_| NetGroupError::InvalidRange(format!("{start}-{end}")))?;
+            IpAddr::V6(Ipv6Addr::from(segments))
+        }
+        _ => parse_addr(end)?,
+    };
+    if first.is_ipv4() != last.is_ipv4() {
+        return Err(NetGroupError::MixedFamilies(first, last));
+    }
+    if last < first {
+        return Err(NetGroupError::ReversedRange(first, last));
+    }
+    Ok(first..=last)
+}
+
+// "24" or a dotted netmask "255.255.255.0"
+fn prefix_len_from_str(addr: IpAddr, mask: &str) -> Result<u8, NetGroupError> {
+    if let (IpAddr::V4(_), Ok(netmask)) = (addr, mask.parse::<Ipv4Addr>()) {
+        let bits = u32::from(netmask);
+        let ones = bits.leading_ones();
+        if u32::MAX.checked_shl(32 - ones).unwrap_or(0) != bits {
+            return Err(NetGroupError::NonContiguousNetmask(mask.to_string()));
+        }
+        return Ok(ones as u8);
+    }
+    mask.parse::<u8>()
+        .map_err(|_| NetGroupError::InvalidPrefixLen(mask.to_string()))
+}
+
+// "10.1.*.*" => 10.1.0.0/16. wildcards must be the trailing octets.
+fn wildcard_from_str(s: &str) -> Result<IpNet, NetGroupError> {
+    let invalid = || NetGroupError::InvalidWildcard(s.to_string());
+    let parts = s.split('.').collect::<Vec<_>>();
+    if parts.len() != 4 {
+        return Err(invalid());
+    }
+    let fixed = parts.iter().take_while(|p| **p != "*").count();
+    if parts[fixed..].iter().any(|p| *p != "*") {
+        return Err(invalid());
+    }
+    let mut octets = [0u8; 4];
+    for (octet, part) in octets.iter_mut().zip(&parts[..fixed]) {
+        *octet = part.parse().map_err(|_| invalid())?;
+    }
+    Ipv4Net::new(Ipv4Addr::from(octets), (fixed * 8) as u8)
+        .map(IpNet::V4)
+        .map_err(|_| invalid())
+}
+
+// convert a network group string to NetGroup. accepted forms:
+//   IpAddr            10.0.0.1, fd00::1
+//   IpNet             10.0.0.0/8, 10.0.0.0/255.0.0.0, fd00::/8
+//   RangeInclusive    10.0.0.1..=10.0.0.9, 10.0.0.1-10.0.0.9, 10.0.0.1-9
+//   wildcard          10.1.*.*
+// text after '#' is a comment.
 impl FromStr for NetGroup {
-    type Err = String;
+    type Err = NetGroupError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.contains('/') {
-            if let Ok(ipnet) = s.parse::<IpNet>() {
-                return Ok(NetGroup::IpNet(ipnet));
-            }
-        } else if s.contains("..=") {
-            if let Ok(range) = rangeinclusive_ipaddr_from_str(s) {
-                if let Ok(net_for_range) = rangeinclusive_to_ipnet(&range) {
-                    return Ok(NetGroup::IpRange((net_for_range, range)));
-                }
-            }
-        } else if s.contains('.') {
-            if let Ok(ipv4addr) = s.parse::<Ipv4Addr>() {
-                if let Ok(ipv4net) = Ipv4Net::new(ipv4addr, 32) {
-                    return Ok(NetGroup::IpNet(IpNet::V4(ipv4net)));
-                }
-            }
-        } else if s.contains(':') {
-            if let Ok(ipv6addr) = s.parse::<Ipv6Addr>() {
-                if let Ok(ipv6net) = Ipv6Net::new(ipv6addr, 128) {
-                    return Ok(NetGroup::IpNet(IpNet::V6(ipv6net)));
-                }
+        let s = s.split('#').next().unwrap_or_default().trim();
+        if s.is_empty() {
+            return Err(NetGroupError::Empty);
+        }
+        let range = s.split_once("..=").or_else(|| s.split_once('-'));
+        if let Some((start, end)) = range {
+            let range = rangeinclusive_ipaddr_from_str(start, end)?;
+            let net_for_range = rangeinclusive_to_ipnet(&range)
+                .map_err(|_| NetGroupError::InvalidRange(s.to_string()))?;
+            return Ok(NetGroup::IpRange((net_for_range, range)));
+        }
+        if s.contains('*') {
+            return wildcard_from_str(s).map(NetGroup::IpNet);
+        }
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, mask)) => {
+                let addr = parse_addr(addr)?;
+                (addr, Some(prefix_len_from_str(addr, mask.trim())?))
             }
+            None => (parse_addr(s)?, None),
+        };
+        let net = match addr {
+            IpAddr::V4(x) => Ipv4Net::new(x, prefix_len.unwrap_or(32)).map(IpNet::V4),
+            IpAddr::V6(x) => Ipv6Net::new(x, prefix_len.unwrap_or(128)).map(IpNet::V6),
+        };
+        net.map(NetGroup::IpNet).map_err(|_| {
+            NetGroupError::InvalidPrefixLen(prefix_len.map_or_else(String::new, |p| p.to_string()))
+        })
+    }
+}
+
+// one line of a network group list: the group and an optional label after it.
+//   10.0.0.0/8 office
+//   1.2.3.0/24 ; SBL123
+//   192.168.1.1 - 192.168.1.9, printers   # comment
+// `None` for empty and comment lines.
+pub fn parse_line(line: &str) -> Result<Option<(NetGroup, Option<String>)>, NetGroupError> {
+    let line = line.split('#').next().unwrap_or_default().trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    // "a - b" ranges are written without the spaces around the dash
+    let line = line.replace(" - ", "-");
+    let (group, label) = match line.split_once([' ', '\t', ',', ';']) {
+        Some((group, label)) => (group, label.trim_matches([' ', '\t', ',', ';'])),
+        None => (line.as_str(), ""),
+    };
+    let group = NetGroup::from_str(group)?;
+    Ok(Some((
+        group,
+        (!label.is_empty()).then(|| label.to_string()),
+    )))
+}
+
+// parse a network group list with `parse_line`. labels are dropped.
+pub fn parse_list(source: &str) -> Result<Vec<NetGroup>> {
+    Ok(parse_labeled_list(source)?
+        .into_iter()
+        .map(|(net, _)| net)
+        .collect())
+}
+
+// parse a network group list with `parse_line`, keeping the labels.
+pub fn parse_labeled_list(source: &str) -> Result<Vec<(NetGroup, Option<String>)>> {
+    let mut entries = Vec::new();
+    for (idx, line) in source.lines().enumerate() {
+        if let Some(entry) = parse_line(line).map_err(|e| anyhow!("line {}: {e}", idx + 1))? {
+            entries.push(entry);
         }
-        Err(format!("invalid network group {s}"))
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        parse_line, rangeinclusive_to_ipnet, rangeinclusive_to_ipnets, NetGroup, NetGroupError,
+    };
+    use std::net::IpAddr;
+
+    fn parsed(s: &str) -> String {
+        s.parse::<NetGroup>().unwrap().to_string()
+    }
+
+    fn error(s: &str) -> NetGroupError {
+        s.parse::<NetGroup>().unwrap_err()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn vendor_forms() {
+        assert_eq!(parsed("1.2.3.4-1.2.3.9"), "1.2.3.4..=1.2.3.9");
+        assert_eq!(parsed("1.2.3.4-9"), "1.2.3.4..=1.2.3.9");
+        assert_eq!(parsed("2001:db8::1-ff"), "2001:db8::1..=2001:db8::ff");
+        assert_eq!(parsed("10.0.0.0/255.0.0.0"), "10.0.0.0/8");
+        assert_eq!(parsed("10.1.*.*"), "10.1.0.0/16");
+        assert_eq!(parsed("*.*.*.*"), "0.0.0.0/0");
+        assert_eq!(parsed("10.0.0.0/8   # office"), "10.0.0.0/8");
+        assert_eq!(parsed("fd00::1"), "fd00::1/128");
+        assert_eq!(
+            parsed("11.10.1.100..=11.10.1.109"),
+            "11.10.1.100..=11.10.1.109"
+        );
+    }
+
+    #[test]
+    fn typed_errors() {
+        assert_eq!(error(""), NetGroupError::Empty);
+        assert_eq!(error("# comment only"), NetGroupError::Empty);
+        assert_eq!(
+            error("10.0.0.300"),
+            NetGroupError::InvalidAddress("10.0.0.300".to_string())
+        );
+        assert_eq!(
+            error("10.0.0.0/33"),
+            NetGroupError::InvalidPrefixLen("33".to_string())
+        );
+        assert_eq!(
+            error("10.0.0.0/255.0.255.0"),
+            NetGroupError::NonContiguousNetmask("255.0.255.0".to_string())
+        );
+        assert_eq!(
+            error("10.*.1.*"),
+            NetGroupError::InvalidWildcard("10.*.1.*".to_string())
+        );
+        assert_eq!(
+            error("10.0.0.9..=10.0.0.1"),
+            NetGroupError::ReversedRange(ip("10.0.0.9"), ip("10.0.0.1"))
+        );
+        assert_eq!(
+            error("10.0.0.1-::1"),
+            NetGroupError::MixedFamilies(ip("10.0.0.1"), ip("::1"))
+        );
+        assert_eq!(
+            error("1.2.3.4-300"),
+            NetGroupError::InvalidRange("1.2.3.4-300".to_string())
+        );
+    }
+
+    #[test]
+    fn labeled_lines() {
+        let line = |s: &str| {
+            parse_line(s)
+                .unwrap()
+                .map(|(net, label)| (net.to_string(), label))
+        };
+        assert_eq!(
+            line("10.0.0.0/8 office"),
+            Some(("10.0.0.0/8".to_string(), Some("office".to_string())))
+        );
+        assert_eq!(
+            line("1.2.3.0/24 ; SBL123"),
+            Some(("1.2.3.0/24".to_string(), Some("SBL123".to_string())))
+        );
+        assert_eq!(
+            line("192.168.1.1 - 192.168.1.9, printers  # 2nd floor"),
+            Some((
+                "192.168.1.1..=192.168.1.9".to_string(),
+                Some("printers".to_string())
+            ))
+        );
+        assert_eq!(line("\tfd00::/8\t"), Some(("fd00::/8".to_string(), None)));
+        assert_eq!(line("   # comment"), None);
+        assert!(parse_line("10.0.0.0/8x office").is_err());
+    }
+
+    fn cidrs(start: &str, end: &str) -> Vec<String> {
+        let range = start.parse::<IpAddr>().unwrap()..=end.parse::<IpAddr>().unwrap();
+        rangeinclusive_to_ipnets(&range)
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn exact_cidrs() {
+        assert_eq!(
+            cidrs("11.10.1.100", "11.10.1.109"),
+            vec!["11.10.1.100/30", "11.10.1.104/30", "11.10.1.108/31"]
+        );
+        assert_eq!(cidrs("10.0.0.0", "10.255.255.255"), vec!["10.0.0.0/8"]);
+        assert_eq!(cidrs("1.2.3.4", "1.2.3.4"), vec!["1.2.3.4/32"]);
+        assert_eq!(cidrs("0.0.0.0", "255.255.255.255"), vec!["0.0.0.0/0"]);
+        assert_eq!(
+            cidrs("255.255.255.254", "255.255.255.255"),
+            vec!["255.255.255.254/31"]
+        );
+        assert_eq!(
+            cidrs("::", "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"),
+            vec!["::/0"]
+        );
+        assert_eq!(
+            cidrs("2001:db8::1", "2001:db8::ff"),
+            vec![
+                "2001:db8::1/128",
+                "2001:db8::2/127",
+                "2001:db8::4/126",
+                "2001:db8::8/125",
+                "2001:db8::10/124",
+                "2001:db8::20/123",
+                "2001:db8::40/122",
+                "2001:db8::80/121"
+            ]
+        );
+    }
+
+    #[test]
+    fn covering_network() {
+        let net = |s: &str, e: &str| {
+            rangeinclusive_to_ipnet(&(s.parse::<IpAddr>().unwrap()..=e.parse().unwrap()))
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(net("11.10.1.100", "11.10.1.109"), "11.10.1.96/28");
+        assert_eq!(net("1.0.1.0", "1.0.3.10"), "1.0.0.0/22");
+        assert_eq!(net("10.0.0.1", "10.0.0.1"), "10.0.0.1/32");
+        assert_eq!(net("2001:db8::1", "2001:db8::ff"), "2001:db8::/120");
+    }
+
+    #[test]
+    fn invalid_ranges() {
+        let reversed = "10.0.0.9".parse::<IpAddr>().unwrap()..="10.0.0.1".parse().unwrap();
+        assert!(rangeinclusive_to_ipnets(&reversed).is_err());
+        let mixed = "10.0.0.1".parse::<IpAddr>().unwrap()..="::1".parse().unwrap();
+        assert!(rangeinclusive_to_ipnets(&mixed).is_err());
     }
 }
@@ -0,0 +1,272 @@
+use crate::netgroup::{netgroup_from_range, rangeinclusive_to_ipnets, NetGroup};
+use anyhow::Result;
+use ipnet::IpNet;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    ops::RangeInclusive,
+};
+
+// sorted, disjoint and non-adjacent (start, end) address intervals of one family.
+type Intervals = Vec<(u128, u128)>;
+
+// set of addresses given by network groups, kept as merged intervals per address family.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct NetSet {
+    v4: Intervals,
+    v6: Intervals,
+}
+
+impl FromIterator<NetGroup> for NetSet {
+    fn from_iter<I: IntoIterator<Item = NetGroup>>(iter: I) -> Self {
+        let mut v4 = Vec::new();
+        let mut v6 = Vec::new();
+        for net in iter {
+            let (start, end) = net.bounds();
+            let interval = (to_u128(start), to_u128(end));
+            if net.is_ipv4() {
+                v4.push(interval);
+            } else {
+                v6.push(interval);
+            }
+        }
+        Self {
+            v4: normalize(v4),
+            v6: normalize(v6),
+        }
+    }
+}
+
+impl NetSet {
+    pub fn is_empty(&self) -> bool {
+        self.v4.is_empty() && self.v6.is_empty()
+    }
+
+    pub fn union(&self, other: &NetSet) -> NetSet {
+        let union = |x: &Intervals, y: &Intervals| normalize([x.as_slice(), y].concat());
+        NetSet {
+            v4: union(&self.v4, &other.v4),
+            v6: union(&self.v6, &other.v6),
+        }
+    }
+
+    pub fn intersection(&self, other: &NetSet) -> NetSet {
+        NetSet {
+            v4: intersection(&self.v4, &other.v4),
+            v6: intersection(&self.v6, &other.v6),
+        }
+    }
+
+    // addresses of `self` that are not in `other`.
+    pub fn difference(&self, other: &NetSet) -> NetSet {
+        NetSet {
+            v4: difference(&self.v4, &other.v4),
+            v6: difference(&self.v6, &other.v6),
+        }
+    }
+
+    // merged intervals of one address family, as `to_u128` numbers.
+    pub fn intervals(&self, v4: bool) -> &[(u128, u128)] {
+        if v4 {
+            &self.v4
+        } else {
+            &self.v6
+        }
+    }
+
+    // merged address ranges, ipv4 first.
+    pub fn ranges(&self) -> Vec<RangeInclusive<IpAddr>> {
+        let v4 = self
+            .v4
+            .iter()
+            .map(|(s, e)| from_u128(*s, true)..=from_u128(*e, true));
+        let v6 = self
+            .v6
+            .iter()
+            .map(|(s, e)| from_u128(*s, false)..=from_u128(*e, false));
+        v4.chain(v6).collect()
+    }
+
+    // the minimal list of networks covering the set.
+    pub fn ipnets(&self) -> Result<Vec<IpNet>> {
+        let mut nets = Vec::new();
+        for range in self.ranges() {
+            nets.extend(rangeinclusive_to_ipnets(&range)?);
+        }
+        Ok(nets)
+    }
+
+    // one network group per merged range: a network if the range is one, a range otherwise.
+    pub fn netgroups(&self) -> Result<Vec<NetGroup>> {
+        self.ranges().into_iter().map(netgroup_from_range).collect()
+    }
+}
+
+pub fn to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(x) => u128::from(u32::from(x)),
+        IpAddr::V6(x) => u128::from(x),
+    }
+}
+
+fn from_u128(n: u128, v4: bool) -> IpAddr {
+    if v4 {
+        IpAddr::V4(Ipv4Addr::from(n as u32))
+    } else {
+        IpAddr::V6(Ipv6Addr::from(n))
+    }
+}
+
+// sort and merge overlapping or adjacent intervals.
+fn normalize(mut intervals: Intervals) -> Intervals {
+    intervals.sort_unstable();
+    let mut merged: Intervals = Vec::with_capacity(intervals.len());
+    for (start, end) in intervals {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+fn intersection(x: &Intervals, y: &Intervals) -> Intervals {
+    let mut found = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < x.len() && j < y.len() {
+        let start = x[i].0.max(y[j].0);
+        let end = x[i].1.min(y[j].1);
+        if start <= end {
+            found.push((start, end));
+        }
+        if x[i].1 < y[j].1 {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    found
+}
+
+fn difference(x: &Intervals, y: &Intervals) -> Intervals {
+    let mut found = Vec::new();
+    let mut j = 0;
+    for &(start, end) in x {
+        // intervals of `y` ending before this one can not overlap the next ones either.
+        while j < y.len() && y[j].1 < start {
+            j += 1;
+        }
+        let mut cur = Some(start);
+        for &(s, e) in &y[j..] {
+            let Some(from) = cur else {
+                break;
+            };
+            if s > end {
+                break;
+            }
+            if s > from {
+                found.push((from, s - 1));
+            }
+            cur = e.checked_add(1).filter(|next| *next <= end);
+        }
+        if let Some(from) = cur {
+            found.push((from, end));
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NetSet;
+    use crate::netgroup::NetGroup;
+
+    fn set(groups: &[&str]) -> NetSet {
+        groups
+            .iter()
+            .map(|g| g.parse::<NetGroup>().unwrap())
+            .collect()
+    }
+
+    fn cidrs(set: &NetSet) -> Vec<String> {
+        set.ipnets()
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn aggregate() {
+        let set = set(&[
+            "10.0.0.128/25",
+            "10.0.0.0/25",
+            "10.0.1.0..=10.0.1.255",
+            "10.0.1.7",
+            "192.168.0.10..=192.168.0.20",
+            "192.168.0.15..=192.168.0.30",
+            "fd00::/9",
+            "fd80::/9",
+        ]);
+        assert_eq!(
+            cidrs(&set),
+            vec![
+                "10.0.0.0/23",
+                "192.168.0.10/31",
+                "192.168.0.12/30",
+                "192.168.0.16/29",
+                "192.168.0.24/30",
+                "192.168.0.28/31",
+                "192.168.0.30/32",
+                "fd00::/8"
+            ]
+        );
+        let groups = set
+            .netgroups()
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            groups,
+            vec!["10.0.0.0/23", "192.168.0.10..=192.168.0.30", "fd00::/8"]
+        );
+    }
+
+    #[test]
+    fn set_algebra() {
+        let allow = set(&["10.0.0.0/24", "10.0.2.0/24", "fd00::/64"]);
+        let deny = set(&["10.0.0.128/26", "10.0.1.0..=10.0.2.9", "fd00::1"]);
+
+        assert_eq!(
+            cidrs(&allow.union(&deny)),
+            vec!["10.0.0.0/23", "10.0.2.0/24", "fd00::/64"]
+        );
+        assert_eq!(
+            cidrs(&allow.intersection(&deny)),
+            vec!["10.0.0.128/26", "10.0.2.0/29", "10.0.2.8/31", "fd00::1/128"]
+        );
+        let difference = allow.difference(&deny);
+        assert_eq!(
+            difference
+                .ranges()
+                .iter()
+                .map(|r| format!("{r:?}"))
+                .collect::<Vec<_>>(),
+            vec![
+                "10.0.0.0..=10.0.0.127",
+                "10.0.0.192..=10.0.0.255",
+                "10.0.2.10..=10.0.2.255",
+                "fd00::..=fd00::",
+                "fd00::2..=fd00::ffff:ffff:ffff:ffff"
+            ]
+        );
+        assert!(deny.difference(&deny).is_empty());
+        assert!(set(&["10.0.0.0/8"])
+            .intersection(&set(&["::/0"]))
+            .is_empty());
+        assert_eq!(
+            cidrs(&set(&["::/0"]).difference(&set(&["::/1"]))),
+            vec!["8000::/1"]
+        );
+    }
+}
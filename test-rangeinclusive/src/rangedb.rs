@@ -0,0 +1,175 @@
+use crate::{
+    mapper::{Match, SearchTree},
+    netgroup::{netgroup_from_range, NetGroup},
+};
+use anyhow::{anyhow, bail, Result};
+use csv::StringRecord;
+use std::{
+    io::Read,
+    net::{IpAddr, Ipv4Addr},
+    str::FromStr,
+};
+
+// address ranges and their attributes, e.g. country and AS number, loaded from an offline
+// database in csv. each row is one of
+//   start_ip,end_ip,attrs...      1.0.0.0,1.0.0.255,AU,13335
+//   network,attrs...              1.0.0.0/24,AU,13335
+//   start,end,attrs...            16777216,16777471,AU,13335 (ipv4 as integers)
+// a first row that is none of them is the header, naming the attributes.
+#[derive(Debug)]
+pub struct RangeDb {
+    columns: Vec<String>,
+    tree: Option<SearchTree<Vec<String>>>,
+    len: usize,
+}
+
+impl RangeDb {
+    pub fn open(path: &str) -> Result<Self> {
+        let file = std::fs::File::open(path).map_err(|e| anyhow!("fail to open {path}. {e}"))?;
+        Self::from_reader(file).map_err(|e| anyhow!("{path} {e}"))
+    }
+
+    pub fn from_reader<R: Read>(rdr: R) -> Result<Self> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .from_reader(rdr);
+        let mut header = None;
+        let mut columns = Vec::new();
+        let mut entries = Vec::new();
+        for (idx, record) in rdr.records().enumerate() {
+            let record = record.map_err(|e| anyhow!("line {}: {e}", idx + 1))?;
+            match parse_record(&record) {
+                Ok((net, skip, attrs)) => {
+                    if let Some(header) = header.take() {
+                        columns = header_columns(&header, skip);
+                    }
+                    entries.push((net, attrs));
+                }
+                Err(_) if idx == 0 && is_header(&record) => header = Some(record),
+                Err(e) => bail!("line {}: {e}", idx + 1),
+            }
+        }
+        let len = entries.len();
+        Ok(Self {
+            columns,
+            tree: SearchTree::from_entries(entries),
+            len,
+        })
+    }
+
+    // names of the attributes, from the header. empty without a header.
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // the most specific range containing `ip` and its attributes.
+    pub fn lookup(&self, ip: IpAddr) -> Option<Match<'_, Vec<String>>> {
+        self.tree.as_ref()?.most_specific(ip)
+    }
+}
+
+// a header starts with a name, not an address, a network or an integer.
+fn is_header(record: &StringRecord) -> bool {
+    let first = record.get(0).unwrap_or_default();
+    !first.contains('/') && first.parse::<IpAddr>().is_err() && first.parse::<u32>().is_err()
+}
+
+fn header_columns(header: &StringRecord, skip: usize) -> Vec<String> {
+    header.iter().skip(skip).map(str::to_string).collect()
+}
+
+// the network group, the number of fields giving it, and the attributes after them.
+fn parse_record(record: &StringRecord) -> Result<(NetGroup, usize, Vec<String>)> {
+    let first = record.get(0).unwrap_or_default();
+    let second = record.get(1).unwrap_or_default();
+    let (net, skip) = if first.contains('/') {
+        let net = NetGroup::from_str(first).map_err(|e| anyhow!(e))?;
+        (net, 1)
+    } else if let (Ok(start), Ok(end)) = (first.parse::<u32>(), second.parse::<u32>()) {
+        let range = IpAddr::V4(Ipv4Addr::from(start))..=IpAddr::V4(Ipv4Addr::from(end));
+        (range_netgroup(range)?, 2)
+    } else {
+        let start = IpAddr::from_str(first).map_err(|e| anyhow!("invalid start {first}. {e}"))?;
+        let end = IpAddr::from_str(second).map_err(|e| anyhow!("invalid end {second}. {e}"))?;
+        (range_netgroup(start..=end)?, 2)
+    };
+    let attrs = record.iter().skip(skip).map(str::to_string).collect();
+    Ok((net, skip, attrs))
+}
+
+fn range_netgroup(range: std::ops::RangeInclusive<IpAddr>) -> Result<NetGroup> {
+    let (start, end) = (*range.start(), *range.end());
+    if start.is_ipv4() != end.is_ipv4() {
+        bail!("mixed address families in range {start}..={end}");
+    }
+    if end < start {
+        bail!("reversed range {start}..={end}");
+    }
+    netgroup_from_range(range)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RangeDb;
+    use crate::netgroup::NetGroup;
+
+    fn lookup(db: &RangeDb, ip: &str) -> Option<(String, Vec<String>)> {
+        db.lookup(ip.parse().unwrap())
+            .map(|m| (m.group.to_string(), m.value.clone()))
+    }
+
+    #[test]
+    fn csv_formats() {
+        let ranges = "start_ip,end_ip,country,asn
+1.0.0.0,1.0.0.255,AU,13335
+1.0.1.0, 1.0.3.10 ,CN,4134
+2001:db8::,2001:db8::ffff,ZZ,64496
+";
+        let db = RangeDb::from_reader(ranges.as_bytes()).unwrap();
+        assert_eq!(db.len(), 3);
+        assert_eq!(db.columns(), ["country", "asn"]);
+        let (group, attrs) = lookup(&db, "1.0.0.7").unwrap();
+        assert_eq!(group, "1.0.0.0/24");
+        assert_eq!(attrs, ["AU", "13335"]);
+        assert_eq!(lookup(&db, "1.0.3.10").unwrap().0, "1.0.1.0..=1.0.3.10");
+        assert!(lookup(&db, "1.0.3.11").is_none());
+        assert_eq!(lookup(&db, "2001:db8::1").unwrap().1, ["ZZ", "64496"]);
+
+        let networks = "network,geoname_id,country
+1.0.0.0/24,2077456,AU
+\"1.0.8.0/21\",1814991,CN
+";
+        let db = RangeDb::from_reader(networks.as_bytes()).unwrap();
+        assert_eq!(db.columns(), ["geoname_id", "country"]);
+        assert_eq!(lookup(&db, "1.0.15.255").unwrap().1, ["1814991", "CN"]);
+
+        // ipv4 ranges as integers, without a header
+        let integers = "\"16777216\",\"16777471\",\"AU\",\"Australia\"
+\"16777472\",\"16778239\",\"CN\",\"China\"
+";
+        let db = RangeDb::from_reader(integers.as_bytes()).unwrap();
+        assert!(db.columns().is_empty());
+        let (group, attrs) = lookup(&db, "1.0.2.0").unwrap();
+        assert_eq!(group, "1.0.1.0..=1.0.3.255");
+        assert_eq!(attrs, ["CN", "China"]);
+        assert!(matches!(
+            db.lookup("1.0.0.1".parse().unwrap()).map(|m| m.group),
+            Some(NetGroup::IpNet(_))
+        ));
+    }
+
+    #[test]
+    fn invalid_rows() {
+        let err = RangeDb::from_reader("1.0.0.0,1.0.0.255,AU\n1.0.1.9,1.0.1.0,CN\n".as_bytes())
+            .unwrap_err();
+        assert_eq!(err.to_string(), "line 2: reversed range 1.0.1.9..=1.0.1.0");
+        assert!(RangeDb::from_reader("1.0.0.0,fd00::1,AU\n".as_bytes()).is_err());
+        assert!(RangeDb::from_reader("a,b\n1.0.0.0/33,AU\n".as_bytes()).is_err());
+    }
+}
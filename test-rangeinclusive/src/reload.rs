@@ -0,0 +1,187 @@
+use crate::{
+    mapper::SearchTree,
+    netgroup::{self, NetGroup},
+};
+use anyhow::{anyhow, Result};
+use arc_swap::ArcSwap;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread::JoinHandle,
+    time::{Duration, SystemTime},
+};
+use tracing::{info, warn};
+
+type Entry = (NetGroup, Option<String>);
+// modification time and length of a file
+type Stamp = Option<(SystemTime, u64)>;
+
+// a labeled search tree built from a list file, replaced when the file changes.
+// readers take the `Arc` of the current snapshot without a lock, and keep querying it
+// while the next one is built. the snapshot is swapped in atomically.
+#[derive(Debug)]
+pub struct LiveTree {
+    path: PathBuf,
+    current: ArcSwap<Snapshot>,
+}
+
+#[derive(Debug)]
+pub struct Snapshot {
+    tree: Option<SearchTree<Option<String>>>,
+    // the number of lines of each entry, to find the changes of the next version
+    entries: HashMap<Entry, usize>,
+    // of the file it was built from
+    stamp: Stamp,
+}
+
+impl Snapshot {
+    pub fn tree(&self) -> Option<&SearchTree<Option<String>>> {
+        self.tree.as_ref()
+    }
+}
+
+impl LiveTree {
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let (entries, stamp) = read_list(&path)?;
+        let tree = SearchTree::from_entries(
+            entries
+                .iter()
+                .flat_map(|(e, n)| std::iter::repeat_n(e.clone(), *n)),
+        );
+        Ok(Self {
+            path,
+            current: ArcSwap::from_pointee(Snapshot {
+                tree,
+                entries,
+                stamp,
+            }),
+        })
+    }
+
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        self.current.load_full()
+    }
+
+    // rebuild the tree if the file was modified, applying only the changed lines to a copy
+    // of the current tree. returns the number of inserted and removed entries, or `None`
+    // if the file is unchanged. on errors, the current tree is kept.
+    // a change still costs a read and parse of the whole file and a deep copy of the
+    // tree, since readers may hold the current one. what is saved is the rebuild of the
+    // tree from every entry.
+    pub fn reload(&self) -> Result<Option<(usize, usize)>> {
+        let old = self.snapshot();
+        if stamp(&self.path)? == old.stamp {
+            return Ok(None);
+        }
+        let (entries, stamp) = read_list(&self.path)?;
+        let mut tree = old.tree.clone();
+        let (mut inserted, mut removed) = (0, 0);
+        for (entry, &n) in &old.entries {
+            for _ in entries.get(entry).copied().unwrap_or_default()..n {
+                if let Some(t) = &mut tree {
+                    t.remove(&entry.0, &entry.1);
+                }
+                removed += 1;
+            }
+        }
+        for (entry, &n) in &entries {
+            for _ in old.entries.get(entry).copied().unwrap_or_default()..n {
+                match &mut tree {
+                    Some(t) => t.insert(entry.0.clone(), entry.1.clone()),
+                    None => tree = SearchTree::from_entries([entry.clone()]),
+                }
+                inserted += 1;
+            }
+        }
+        self.current.store(Arc::new(Snapshot {
+            tree,
+            entries,
+            stamp,
+        }));
+        Ok(Some((inserted, removed)))
+    }
+
+    // check the file every `interval` in a background thread. the thread ends when
+    // every other reference to the tree is dropped.
+    pub fn watch(self: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let live = Arc::clone(self);
+        std::thread::spawn(move || {
+            while Arc::strong_count(&live) > 1 {
+                std::thread::sleep(interval);
+                match live.reload() {
+                    Ok(Some((inserted, removed))) => info!(
+                        "reloaded {}: {inserted} inserted, {removed} removed",
+                        live.path.display()
+                    ),
+                    Ok(None) => {}
+                    Err(e) => warn!("keep the current tree. {e}"),
+                }
+            }
+        })
+    }
+}
+
+fn read_list(path: &Path) -> Result<(HashMap<Entry, usize>, Stamp)> {
+    // the stamp is taken first, so a write during the read is found by the next check
+    let stamp = stamp(path)?;
+    let source = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("fail to read {}. {e}", path.display()))?;
+    let mut entries = HashMap::new();
+    for (idx, line) in source.lines().enumerate() {
+        let entry = netgroup::parse_line(line)
+            .map_err(|e| anyhow!("{} line {}: {e}", path.display(), idx + 1))?;
+        if let Some(entry) = entry {
+            *entries.entry(entry).or_default() += 1;
+        }
+    }
+    Ok((entries, stamp))
+}
+
+fn stamp(path: &Path) -> Result<Stamp> {
+    let metadata =
+        std::fs::metadata(path).map_err(|e| anyhow!("fail to read {}. {e}", path.display()))?;
+    Ok(metadata.modified().ok().map(|m| (m, metadata.len())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LiveTree;
+    use std::sync::Arc;
+
+    #[test]
+    fn reload_changed_list() {
+        let path = std::env::temp_dir().join(format!("live-tree-{}.lst", std::process::id()));
+        std::fs::write(&path, "10.0.0.0/8 private\n10.1.0.0/16 office\n").unwrap();
+        let live = Arc::new(LiveTree::open(&path).unwrap());
+        let before = live.snapshot();
+        assert_eq!(live.reload().unwrap(), None);
+
+        // a different length, so the change is seen within the same mtime tick
+        std::fs::write(
+            &path,
+            "10.0.0.0/8 private\n10.1.2.0/24 lab\n10.1.2.0/24 lab\nfd00::/8\n",
+        )
+        .unwrap();
+        assert_eq!(live.reload().unwrap(), Some((3, 1)));
+        let label = |snapshot: &super::Snapshot, ip: &str| {
+            snapshot
+                .tree()
+                .and_then(|t| t.most_specific(ip.parse().unwrap()))
+                .map(|m| m.value.clone())
+        };
+        let after = live.snapshot();
+        assert_eq!(label(&after, "10.1.2.3"), Some(Some("lab".to_string())));
+        assert_eq!(label(&after, "10.1.9.9"), Some(Some("private".to_string())));
+        assert_eq!(label(&after, "fd00::1"), Some(None));
+        // a reader of the old snapshot is not affected
+        assert_eq!(label(&before, "10.1.9.9"), Some(Some("office".to_string())));
+
+        // an invalid list keeps the current tree
+        std::fs::write(&path, "10.0.0.0/8 private\n10.0.0.300\n").unwrap();
+        assert!(live.reload().unwrap_err().to_string().contains("line 2"));
+        assert_eq!(label(&live.snapshot(), "fd00::1"), Some(None));
+        std::fs::remove_file(&path).unwrap();
+    }
+}